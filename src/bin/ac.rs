@@ -1,12 +1,16 @@
 use clap::Parser;
 use core_affinity::CoreId;
 use gree::{GreeClientConfig, sync_client::*, *};
-use lib::types::Response;
+use lib::config::{Config, Module};
+use lib::emit::{Emitter, OutputArgs};
+use lib::template::Vars;
 use std::str::FromStr;
 use std::{net::IpAddr, result::Result};
 
+const HELP: &str = "Format variables: {icon}, {state} (on, off)";
+
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, after_help = HELP)]
 struct Args {
     #[arg(long)]
     ip: String,
@@ -22,20 +26,14 @@ struct Args {
 
     #[arg(long, default_value_t = false)]
     listen: bool,
-}
-
-fn output(running: bool) {
-    let text = "";
-    let mut class = "ac-off";
 
-    if running {
-        class = "ac-on";
-    }
+    #[command(flatten)]
+    output: OutputArgs,
+}
 
-    println!(
-        "{}",
-        serde_json::to_string(&Response { class, text: text }).unwrap()
-    );
+fn output(emitter: &mut dyn Emitter, module: &Module, running: bool) {
+    let state = if running { "on" } else { "off" };
+    emitter.print(&module.response(state, "{icon}", "", Vars::new()));
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -52,22 +50,26 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let key = args.key.as_str();
 
     if args.toggle {
-        let r = c.getvars(ip, id, key, &vec![vars::POW])?;
+        let r = c.getvars(ip, id, key, &[vars::POW])?;
 
         if let Some(on) = r.dat[0].as_u64() {
             let alt = if on == 1 { 0 } else { 1 };
-            c.setvars(ip, id, key, &vec![vars::POW], &[Value::Number(alt.into())])?;
+            c.setvars(ip, id, key, &[vars::POW], &[Value::Number(alt.into())])?;
         }
 
         return Ok(());
     }
 
     if args.listen {
+        let module = args
+            .output
+            .module(&Config::load()?, env!("CARGO_BIN_NAME"), &[])?;
+        let mut emitter = args.output.emitter(env!("CARGO_BIN_NAME"));
         loop {
-            if let Ok(r) = c.getvars(ip, id, key, &vec![vars::POW]) {
-                output(r.dat[0].as_u64().unwrap() == 1);
+            if let Ok(r) = c.getvars(ip, id, key, &[vars::POW]) {
+                output(&mut *emitter, &module, r.dat[0].as_u64().unwrap() == 1);
             } else {
-                output(false);
+                output(&mut *emitter, &module, false);
             }
 
             std::thread::sleep(std::time::Duration::from_secs(1));
@@ -1,10 +1,17 @@
 use clap::Parser;
 use core_affinity::CoreId;
-use lib::types::Response;
+use lib::config::{Config, Module};
+use lib::emit::{Emitter, OutputArgs};
+use lib::template::Vars;
+use std::error::Error;
 use std::{fs, path::PathBuf, thread, time::Duration};
 
+const VARIABLES: &[&str] = &["power", "capacity"];
+const HELP: &str = "Format variables: {icon}, {state} (power, power-idle, power-err, capacity, \
+                    capacity-err), {power} in watts, {capacity} in percent";
+
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, after_help = HELP)]
 struct Args {
     /// Read instantaneous power (power_now)
     #[arg(long, default_value_t = false)]
@@ -25,20 +32,16 @@ struct Args {
     /// Run once and exit instead of listening
     #[arg(long, default_value_t = false)]
     once: bool,
+
+    #[command(flatten)]
+    output: OutputArgs,
 }
 
-fn output(text: String, class: &str) {
-    println!(
-        "{}",
-        serde_json::to_string(&Response {
-            class,
-            text: text.as_str()
-        })
-        .unwrap()
-    );
+fn output(emitter: &mut dyn Emitter, module: &Module, state: &str, vars: Vars) {
+    emitter.print(&module.response(state, "{icon}[{power:.1}W][{capacity}%]", "", vars));
 }
 
-fn read_power(path: &PathBuf) -> Result<Option<String>, ()> {
+fn read_power(path: &PathBuf) -> Result<Option<f64>, ()> {
     let s = fs::read_to_string(path).map_err(|_| ())?;
     let s = s.trim();
     if s.is_empty() {
@@ -52,19 +55,24 @@ fn read_power(path: &PathBuf) -> Result<Option<String>, ()> {
         return Ok(None);
     }
 
-    let watts = (val.abs()) as f64 / 1_000_000.0;
-    Ok(Some(format!("{:.1}W", watts)))
+    Ok(Some((val.abs()) as f64 / 1_000_000.0))
 }
 
-fn read_capacity(path: &PathBuf) -> Result<String, ()> {
+fn read_capacity(path: &PathBuf) -> Result<i64, ()> {
     let s = fs::read_to_string(path).map_err(|_| ())?;
     let s = s.trim();
     if s.is_empty() {
         return Err(());
     }
 
-    let val = s.parse::<i64>().map_err(|_| ())?;
-    Ok(format!("{}%", val))
+    s.parse::<i64>().map_err(|_| ())
+}
+
+fn load_module(args: &Args) -> Result<Module, Box<dyn Error>> {
+    let config = Config::load()?;
+    Ok(args
+        .output
+        .module(&config, env!("CARGO_BIN_NAME"), VARIABLES)?)
 }
 
 fn main() {
@@ -72,6 +80,14 @@ fn main() {
     core_affinity::set_for_current(CoreId { id: 0 });
 
     let args = Args::parse();
+    let module = match load_module(&args) {
+        Ok(module) => module,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    let mut emitter = args.output.emitter(env!("CARGO_BIN_NAME"));
 
     // Determine mode: default to power if neither flag is provided
     if args.power && args.capacity {
@@ -97,18 +113,28 @@ fn main() {
 
         if args.once {
             match read_power(&path) {
-                Ok(Some(text)) => output(text, "power"),
-                Ok(None) => output(String::from(" "), "power-idle"),
-                Err(_) => output("ERR".to_string(), "power-err"),
+                Ok(Some(watts)) => output(
+                    &mut *emitter,
+                    &module,
+                    "power",
+                    Vars::new().set("power", watts),
+                ),
+                Ok(None) => output(&mut *emitter, &module, "power-idle", Vars::new()),
+                Err(_) => output(&mut *emitter, &module, "power-err", Vars::new()),
             }
             return;
         }
 
         loop {
             match read_power(&path) {
-                Ok(Some(text)) => output(text, "power"),
-                Ok(None) => output(String::from(" "), "power-idle"),
-                Err(_) => output("ERR".to_string(), "power-err"),
+                Ok(Some(watts)) => output(
+                    &mut *emitter,
+                    &module,
+                    "power",
+                    Vars::new().set("power", watts),
+                ),
+                Ok(None) => output(&mut *emitter, &module, "power-idle", Vars::new()),
+                Err(_) => output(&mut *emitter, &module, "power-err", Vars::new()),
             }
 
             thread::sleep(Duration::from_secs(args.interval));
@@ -118,16 +144,26 @@ fn main() {
 
         if args.once {
             match read_capacity(&path) {
-                Ok(text) => output(text, "battery"),
-                Err(_) => output("ERR".to_string(), "battery-err"),
+                Ok(capacity) => output(
+                    &mut *emitter,
+                    &module,
+                    "capacity",
+                    Vars::new().set("capacity", capacity),
+                ),
+                Err(_) => output(&mut *emitter, &module, "capacity-err", Vars::new()),
             }
             return;
         }
 
         loop {
             match read_capacity(&path) {
-                Ok(text) => output(text, "battery"),
-                Err(_) => output("ERR".to_string(), "battery-err"),
+                Ok(capacity) => output(
+                    &mut *emitter,
+                    &module,
+                    "capacity",
+                    Vars::new().set("capacity", capacity),
+                ),
+                Err(_) => output(&mut *emitter, &module, "capacity-err", Vars::new()),
             }
 
             thread::sleep(Duration::from_secs(args.interval));
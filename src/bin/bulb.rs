@@ -1,10 +1,14 @@
 use clap::Parser;
 use core_affinity::CoreId;
-use lib::types::Response;
+use lib::config::{Config, Module};
+use lib::emit::{Emitter, OutputArgs};
+use lib::template::Vars;
 use yeelight::{Bulb, Properties, Property};
 
+const HELP: &str = "Format variables: {icon}, {state} (on, bg-on, off)";
+
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, after_help = HELP)]
 struct Args {
     #[arg(long)]
     ip: String,
@@ -29,32 +33,28 @@ struct Args {
 
     #[arg(long, default_value_t = false)]
     listen: bool,
-}
 
-fn output(main: bool, bg: bool) {
-    let mut text = "󰹏";
-    let mut class = "bulb-off";
-
-    if bg {
-        text = "󱩐";
-        class = "bulb-bg-on";
-    }
-
-    if main {
-        text = "";
-        class = "bulb-on";
-    }
+    #[command(flatten)]
+    output: OutputArgs,
+}
 
-    println!(
-        "{}",
-        serde_json::to_string(&Response { class, text: text }).unwrap()
-    );
+fn output(emitter: &mut dyn Emitter, module: &Module, main: bool, bg: bool) {
+    let state = match (main, bg) {
+        (true, _) => "on",
+        (false, true) => "bg-on",
+        (false, false) => "off",
+    };
+    emitter.print(&module.response(state, "{icon}", "", Vars::new()));
 }
 
 async fn run() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
 
     if args.listen {
+        let module = args
+            .output
+            .module(&Config::load()?, env!("CARGO_BIN_NAME"), &[])?;
+        let mut emitter = args.output.emitter(env!("CARGO_BIN_NAME"));
         loop {
             if let Ok(Ok(mut bulb)) = tokio::time::timeout(
                 std::time::Duration::from_secs(2),
@@ -68,12 +68,17 @@ async fn run() -> Result<(), Box<dyn std::error::Error>> {
                 .await
             {
                 if let Some(properties) = properties {
-                    output(properties[0] == "on", properties[1] == "on");
+                    output(
+                        &mut *emitter,
+                        &module,
+                        properties[0] == "on",
+                        properties[1] == "on",
+                    );
                 } else {
-                    output(false, false);
+                    output(&mut *emitter, &module, false, false);
                 }
             } else {
-                output(false, false);
+                output(&mut *emitter, &module, false, false);
             }
 
             std::thread::sleep(std::time::Duration::from_secs(1));
@@ -127,7 +132,7 @@ async fn run() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    Ok(tokio::runtime::Builder::new_current_thread()
+    tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .worker_threads(1)
         .on_thread_start(|| {
@@ -135,5 +140,5 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         })
         .build()
         .unwrap()
-        .block_on(run())?)
+        .block_on(run())
 }
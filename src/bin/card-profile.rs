@@ -0,0 +1,153 @@
+use clap::Parser;
+use core_affinity::CoreId;
+use lib::config::{Config, Module};
+use lib::emit::{Emitter, OutputArgs};
+use lib::pulse::{self, Card, Facility, Pulse};
+use lib::template::Vars;
+use lib::utils::process_signals;
+use std::error::Error;
+
+const VARIABLES: &[&str] = &["card", "profile", "profiles"];
+const HELP: &str = "Format variables: {icon}, {state} (duplex, output, input, off, no-card), \
+                    {card}, {profile} that is active, {profiles} to switch between, one per line";
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None, after_help = HELP)]
+struct Args {
+    /// Card name or index, defaults to the card of the default sink
+    #[arg(long)]
+    card: Option<String>,
+
+    /// Switch to the next available profile and exit
+    #[arg(long, default_value_t = false)]
+    cycle: bool,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+async fn find_card(pulse: &Pulse, name: Option<&str>) -> Result<Option<Card>, Box<dyn Error>> {
+    let cards = pulse.list_cards().await?;
+
+    if let Some(name) = name {
+        return Ok(cards
+            .into_iter()
+            .find(|c| c.name == name || c.index.to_string() == name));
+    }
+
+    let Some(sink) = pulse.output_device().await? else {
+        return Ok(None);
+    };
+
+    // PipeWire names the card by id, PulseAudio only shares device properties
+    let shared = |card: &Card, key: &str| {
+        sink.properties
+            .get(key)
+            .is_some_and(|v| card.properties.get(key) == Some(v))
+    };
+    Ok(cards.into_iter().find(|card| {
+        sink.properties.get("device.id") == Some(&card.index.to_string())
+            || shared(card, "api.bluez5.address")
+            || shared(card, "device.bus_path")
+    }))
+}
+
+fn output(emitter: &mut dyn Emitter, module: &Module, card: Option<&Card>) {
+    let mut state = "no-card";
+    let mut vars = Vars::new();
+
+    if let Some(card) = card {
+        let active = card
+            .active_profile
+            .as_deref()
+            .and_then(|name| card.profiles.get(name));
+
+        state = match active {
+            Some(p) if p.sinks > 0 && p.sources > 0 => "duplex",
+            Some(p) if p.sinks > 0 => "output",
+            Some(p) if p.sources > 0 => "input",
+            _ => "off",
+        };
+
+        let profiles = card
+            .available_profiles()
+            .into_iter()
+            .map(|(name, profile)| {
+                let marker = if card.active_profile.as_deref() == Some(name) {
+                    "●"
+                } else {
+                    "○"
+                };
+                format!("{} {}", marker, profile.description)
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        vars = vars
+            .set("card", card.description().unwrap_or(&card.name))
+            .set("profile", active.map_or("", |p| p.description.as_str()))
+            .set("profiles", profiles);
+    }
+
+    let response = module.response(state, "{icon}", "[{card}][\n{profiles}]", vars);
+    emitter.print(&response.alt(state));
+}
+
+async fn process_events(
+    pulse: &Pulse,
+    emitter: &mut dyn Emitter,
+    module: &Module,
+    name: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    // Subscribe first so that nothing happening in between is missed
+    let events = pulse.subscribe()?;
+
+    output(emitter, module, find_card(pulse, name).await?.as_ref());
+
+    pulse::handle_events(events, emitter, async |emitter, event| {
+        // Cards come and go with Bluetooth, and the default sink decides which card is shown
+        if matches!(event.facility, Facility::Card | Facility::Server) {
+            output(emitter, module, find_card(pulse, name).await?.as_ref());
+        }
+        Ok(())
+    })
+    .await
+}
+
+async fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    let pulse = Pulse::new();
+
+    if args.cycle {
+        let card = find_card(&pulse, args.card.as_deref())
+            .await?
+            .ok_or("No card found")?;
+        if let Some(profile) = card.next_profile() {
+            pulse.set_card_profile(&card.name, profile).await?;
+        }
+
+        return Ok(());
+    }
+
+    let module = args
+        .output
+        .module(&Config::load()?, env!("CARGO_BIN_NAME"), VARIABLES)?;
+    let mut emitter = args.output.emitter(env!("CARGO_BIN_NAME"));
+    tokio::select! {
+        result = process_signals() => result,
+        result = process_events(&pulse, &mut *emitter, &module, args.card.as_deref()) => result,
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .worker_threads(1)
+        .on_thread_start(|| {
+            core_affinity::set_for_current(CoreId { id: 0 });
+        })
+        .build()
+        .unwrap()
+        .block_on(run(args))
+}
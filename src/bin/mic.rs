@@ -1,66 +1,218 @@
+use clap::Parser;
 use core_affinity::CoreId;
-use lib::pulse::is_input_muted;
-use lib::types::Response;
+use lib::config::{Config, Module};
+use lib::emit::{Emitter, OutputArgs};
+use lib::pulse::{self, Device, EventKind, Facility, Pulse, Source, SourceOutput};
+use lib::template::Vars;
 use lib::utils::process_signals;
 use std::error::Error;
-use std::process::Stdio;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::{Child, Command};
 
-fn output(muted: bool) {
-    let mut text = "";
-    let mut class = "not-muted";
+const VARIABLES: &[&str] = &["volume", "level", "device", "apps", "count"];
+const HELP: &str = "Format variables: {icon}, {state} (muted, not-muted, in-use, \
+                    muted-in-use while apps record from the muted mic), {volume} in \
+                    percent, {level}, {device}, {apps} recording, one per line, {count} of them";
 
-    if muted {
-        text = "";
-        class = "muted";
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None, after_help = HELP)]
+struct Args {
+    /// Show the volume next to an icon, with a class per volume level
+    #[arg(long, default_value_t = false)]
+    percentage: bool,
+
+    /// Toggle mute and exit
+    #[arg(long, default_value_t = false, conflicts_with_all = ["up", "down"])]
+    toggle: bool,
+
+    /// Raise the volume by this many percent and exit
+    #[arg(long, value_name = "STEP", conflicts_with = "down")]
+    up: Option<u32>,
+
+    /// Lower the volume by this many percent and exit
+    #[arg(long, value_name = "STEP")]
+    down: Option<u32>,
+
+    /// Never raise the volume above this percentage
+    #[arg(long, value_name = "PERCENT", default_value_t = 100)]
+    max: u32,
+
+    /// Make the next source the default and exit
+    #[arg(long, default_value_t = false, conflicts_with_all = ["menu", "select"])]
+    next: bool,
+
+    /// Print the sources to pick from, one per line, for a dmenu-style launcher
+    #[arg(long, default_value_t = false, conflicts_with = "select")]
+    menu: bool,
+
+    /// Make this source the default and exit. Takes a name, an index or a line printed by --menu
+    #[arg(long, value_name = "SOURCE")]
+    select: Option<String>,
+
+    /// With --next or --select, move existing streams to the new default
+    #[arg(long, default_value_t = false)]
+    move_streams: bool,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+/// A tooltip line like "Discord (Discord, pid 3310)".
+fn describe(stream: &SourceOutput) -> String {
+    let name = stream.application_name().unwrap_or("Unknown application");
+    match (stream.binary(), stream.pid()) {
+        (Some(binary), Some(pid)) => format!("{} ({}, pid {})", name, binary, pid),
+        (Some(binary), None) => format!("{} ({})", name, binary),
+        (None, Some(pid)) => format!("{} (pid {})", name, pid),
+        (None, None) => name.to_string(),
+    }
+}
+
+fn output(
+    emitter: &mut dyn Emitter,
+    module: &Module,
+    source: Option<&Source>,
+    recording: &[SourceOutput],
+    percentage: bool,
+) {
+    // Apps holding the mic open stay visible while it is muted
+    let muted = source.is_none_or(|source| source.mute);
+    let state = match (muted, recording.is_empty()) {
+        (false, false) => "in-use",
+        (false, true) => "not-muted",
+        (true, false) => "muted-in-use",
+        (true, true) => "muted",
+    };
+    let format = if percentage {
+        "{icon} {volume}%"
+    } else {
+        "{icon}"
+    };
+
+    let apps = recording
+        .iter()
+        .map(describe)
+        .collect::<Vec<_>>()
+        .join("\n");
+    let mut vars = Vars::new()
+        .set("apps", apps)
+        .set("count", recording.len() as i64);
+    if let Some(source) = source {
+        vars = vars
+            .set("volume", source.volume.percent())
+            .set("level", source.volume.level())
+            .set("device", source.description.as_str());
     }
 
-    println!(
-        "{}",
-        serde_json::to_string(&Response { class, text }).unwrap()
-    );
+    let mut response = module.response(state, format, "{apps}", vars).alt(state);
+    if let Some(source) = source {
+        response = response
+            .class(source.volume.level())
+            .percentage(source.volume.percent());
+    }
+
+    emitter.print(&response);
 }
 
-async fn process_lines(child: &mut Child) -> Result<(), Box<dyn Error>> {
-    let stdout = child
-        .stdout
-        .take()
-        .expect("Child process stdout is not available");
-    let mut lines = BufReader::new(stdout).lines();
+async fn process_events(
+    pulse: &Pulse,
+    emitter: &mut dyn Emitter,
+    module: &Module,
+    percentage: bool,
+) -> Result<(), Box<dyn Error>> {
+    // Subscribe first so that nothing happening in between is missed
+    let events = pulse.subscribe()?;
+
+    let mut source = pulse.input_device().await?;
+    let mut recording = pulse.recording_streams().await?;
+    output(emitter, module, source.as_ref(), &recording, percentage);
+
+    pulse::handle_events(events, emitter, async |emitter, event| {
+        if event.facility == Facility::SourceOutput {
+            recording = pulse.recording_streams().await?;
+            output(emitter, module, source.as_ref(), &recording, percentage);
+            return Ok(());
+        }
 
-    while let Some(line) = lines.next_line().await? {
-        if !line.contains("Event 'change' on source") {
-            continue;
+        let refresh = match (event.facility, event.kind) {
+            // The default source may have switched
+            (Facility::Server, _) | (Facility::Source, EventKind::New | EventKind::Remove) => true,
+            // Only changes to the default source itself matter
+            (Facility::Source, EventKind::Change) => source
+                .as_ref()
+                .is_none_or(|source| event.index == Some(source.index)),
+            _ => false,
+        };
+        if refresh {
+            source = pulse.input_device().await?;
+            output(emitter, module, source.as_ref(), &recording, percentage);
         }
+        Ok(())
+    })
+    .await
+}
+
+async fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    let pulse = Pulse::new();
 
-        output(is_input_muted().await?);
+    // One-shot actions for waybar's on-click and on-scroll handlers
+    let device = Device::Source;
+    if args.toggle {
+        return pulse.toggle_mute(device, device.default_name()).await;
+    }
+    if let Some(step) = args.up {
+        pulse
+            .change_volume(device, device.default_name(), step as i32, args.max)
+            .await?;
+        return Ok(());
+    }
+    if let Some(step) = args.down {
+        pulse
+            .change_volume(device, device.default_name(), -(step as i32), args.max)
+            .await?;
+        return Ok(());
     }
 
-    Ok(())
-}
+    // Switching the default, for the device picker
+    if args.next || args.menu || args.select.is_some() {
+        let sources = pulse
+            .list_sources()
+            .await?
+            .into_iter()
+            // Monitors record what's playing, they aren't microphones
+            .filter(|s| s.monitor_of_sink.is_none())
+            .collect::<Vec<_>>();
 
-async fn run() -> Result<(), Box<dyn Error>> {
-    output(is_input_muted().await?);
+        if args.menu {
+            for source in &sources {
+                println!("{}", pulse::menu_entry(source));
+            }
+            return Ok(());
+        }
 
-    let mut child = Command::new("pactl")
-        .arg("subscribe")
-        .stdout(Stdio::piped())
-        .spawn()?;
+        let choice = match args.select {
+            Some(choice) => pulse::find(&sources, &choice).ok_or("No such source")?,
+            None => {
+                pulse::next(&sources, &pulse.default_source().await?).ok_or("No sources found")?
+            }
+        };
+        return pulse
+            .set_default_source(&choice.name, args.move_streams)
+            .await;
+    }
 
+    let module = args
+        .output
+        .module(&Config::load()?, env!("CARGO_BIN_NAME"), VARIABLES)?;
+    let mut emitter = args.output.emitter(env!("CARGO_BIN_NAME"));
     tokio::select! {
         result = process_signals() => result,
-        result = process_lines(&mut child) => result,
-    }?;
-
-    // Ensure the child process is killed
-    let _ = child.kill().await;
-
-    Ok(())
+        result = process_events(&pulse, &mut *emitter, &module, args.percentage) => result,
+    }
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    Ok(tokio::runtime::Builder::new_current_thread()
+    let args = Args::parse();
+
+    tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .worker_threads(1)
         .on_thread_start(|| {
@@ -68,5 +220,5 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         })
         .build()
         .unwrap()
-        .block_on(run())?)
+        .block_on(run(args))
 }
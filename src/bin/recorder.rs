@@ -1,13 +1,20 @@
 use clap::Parser;
 use core_affinity::CoreId;
-use lib::kv::Kv;
-use lib::types::Response;
-use std::process::Command;
+use futures::StreamExt;
+use lib::config::{Config, Module};
+use lib::emit::{self, Emitter, OutputArgs};
+use lib::kv::{Backend, Kv, KvError};
+use lib::template::Vars;
+use std::error::Error;
+use std::process::{Child, Command};
 use std::string::String;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const HELP: &str = "Format variables: {icon}, {state} (recording, not-recording, error), \
+                    {elapsed} as MM:SS, or e.g. {elapsed:%H:%M:%S}";
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, after_help = HELP)]
 struct Args {
     #[arg(long, default_value_t = false)]
     record: bool,
@@ -17,67 +24,186 @@ struct Args {
 
     #[arg(long, default_value_t = false)]
     listen: bool,
+
+    /// Where the recording state is kept, must match across invocations
+    #[arg(long, value_enum, default_value_t = Backend::Sqlite)]
+    backend: Backend,
+
+    #[command(flatten)]
+    output: OutputArgs,
 }
 
-fn output(secs: Option<i64>) {
-    let mut text = String::new();
-    let mut class = "not-recording";
+fn output(emitter: &mut dyn Emitter, module: &Module, secs: Option<i64>) {
+    let (state, vars) = match secs {
+        Some(secs) => (
+            "recording",
+            Vars::new().set("elapsed", Duration::from_secs(secs.max(0) as u64)),
+        ),
+        None => ("not-recording", Vars::new()),
+    };
+    emitter.print(&module.response(state, "{icon}[   {elapsed}]", "", vars));
+}
 
-    if let Some(secs) = secs {
-        text = format!("   {:0>2}:{:0>2}", secs / 60, secs - (secs / 60));
-        class = "recording";
+fn output_degraded(emitter: &mut dyn Emitter, module: &Module) {
+    emitter.print(&module.response("error", "{icon}", "", Vars::new()));
+}
+
+fn elapsed(db: &Kv) -> Result<Option<i64>, KvError> {
+    match db.get_bool("recording") {
+        Ok(true) => {}
+        Ok(false) | Err(KvError::NotFound) => return Ok(None),
+        Err(e) => return Err(e),
     }
 
-    println!(
-        "{}",
-        serde_json::to_string(&Response {
-            class,
-            text: text.as_str()
-        })
-        .unwrap()
-    );
+    let since_the_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+
+    let start = db.namespace("recording").get_i64("start")?;
+    Ok(Some(since_the_epoch.as_secs() as i64 - start))
 }
 
-fn main() {
-    // Pin to 0 CPU (E-Core)
-    core_affinity::set_for_current(CoreId { id: 0 });
+async fn listen(
+    backend: Backend,
+    emitter: &mut dyn Emitter,
+    module: &Module,
+) -> Result<(), Box<dyn Error>> {
+    loop {
+        // Keep retrying the database so a locked or missing rice.db only degrades the module
+        let Ok(db) = Kv::open(backend) else {
+            output_degraded(emitter, module);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            continue;
+        };
 
-    let db = Kv::new();
+        let Ok(changes) = db.watch("recording") else {
+            output_degraded(emitter, module);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            continue;
+        };
+        let mut changes = std::pin::pin!(changes);
 
-    let args = Args::parse();
+        loop {
+            let state = elapsed(&db);
+            match state {
+                Ok(secs) => output(emitter, module, secs),
+                Err(_) => output_degraded(emitter, module),
+            }
 
-    if args.stop {
-        if let Ok(pid) = db.get_i64("recording:pid") {
-            db.del("recording:pid");
-            Command::new("kill")
-                .arg(pid.to_string())
-                .output()
-                .expect("failed to execute process");
-            db.put_bool("recording", false);
-        }
+            // Only the running clock and error retries need a timer, otherwise sleep until a write
+            let ticking = !matches!(state, Ok(None));
 
-        return;
+            tokio::select! {
+                change = changes.next() => {
+                    if !matches!(change, Some(Ok(_))) {
+                        break;
+                    }
+                }
+                _ = tokio::time::sleep(Duration::from_secs(1)), if ticking => {}
+                // Rendered again above, which the heartbeat lets through
+                _ = emit::sleep_until(emitter.due()) => {}
+            }
+        }
     }
+}
+
+/// Marks a recording as started at `now`, unless one already is.
+fn claim_start(db: &Kv, now: i64) -> Result<bool, KvError> {
+    // Two racing invocations must not both start wf-recorder
+    db.transaction(|tx| {
+        if !tx.compare_and_set("recording", Some(false), true)?
+            && !tx.compare_and_set("recording", None, true)?
+        {
+            return Ok(false);
+        }
+
+        // The pid is only written once wf-recorder runs, drop the one of the last recording
+        let recording = tx.namespace("recording");
+        recording.put_i64("start", now)?;
+        recording.del("pid")?;
+        Ok(true)
+    })
+}
+
+#[derive(Debug, PartialEq)]
+enum Stop {
+    Idle,
+    /// Claimed but wf-recorder has no pid yet
+    Starting,
+    Running(i64),
+}
+
+/// Takes the pid of the running recorder, if there is one.
+fn claim_stop(db: &Kv) -> Result<Stop, KvError> {
+    // Claim the pid atomically so a double-click only sends one kill
+    db.transaction(|tx| {
+        let recording = tx.namespace("recording");
+        let pid = match recording.get_i64("pid") {
+            Ok(pid) => pid,
+            Err(KvError::NotFound) => {
+                return match tx.get_bool("recording") {
+                    Ok(true) => Ok(Stop::Starting),
+                    Ok(false) | Err(KvError::NotFound) => Ok(Stop::Idle),
+                    Err(e) => Err(e),
+                };
+            }
+            Err(e) => return Err(e),
+        };
+
+        recording.del("pid")?;
+        Ok(Stop::Running(pid))
+    })
+}
+
+fn spawn_recorder(dimensions: &str, secs: u64) -> Result<Child, Box<dyn Error>> {
+    let videos = xdg_user::videos()?.ok_or("no XDG videos directory")?;
+    let child = Command::new("wf-recorder")
+        .arg("-g")
+        .arg(dimensions)
+        .arg("-x")
+        .arg("yuv420p")
+        .arg("-f")
+        .arg(format!("{}/{}.mp4", videos.to_string_lossy(), secs))
+        .spawn()?;
+    Ok(child)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    // Pin to 0 CPU (E-Core)
+    core_affinity::set_for_current(CoreId { id: 0 });
+
+    let args = Args::parse();
 
     if args.listen {
-        loop {
-            if let Ok(recording) = db.get_bool("recording") {
-                if recording {
-                    let since_the_epoch = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .expect("Time went backwards");
-
-                    let start = db.get_i64("recording:start").unwrap();
-                    let duration = since_the_epoch.as_secs() as i64 - start;
-
-                    output(Some(duration));
-                } else {
-                    output(None);
-                }
+        let module = args
+            .output
+            .module(&Config::load()?, env!("CARGO_BIN_NAME"), &["elapsed"])?;
+        let mut emitter = args.output.emitter(env!("CARGO_BIN_NAME"));
+        return tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(listen(args.backend, &mut *emitter, &module));
+    }
 
-                std::thread::sleep(std::time::Duration::from_millis(350));
+    let db = Kv::open(args.backend)?;
+    let recording = db.namespace("recording");
+
+    if args.stop {
+        match claim_stop(&db)? {
+            Stop::Idle => {}
+            // Killing now would miss wf-recorder, the click is dropped instead
+            Stop::Starting => eprintln!("recorder: still starting, try again"),
+            Stop::Running(pid) => {
+                Command::new("kill")
+                    .arg(pid.to_string())
+                    .output()
+                    .expect("failed to execute process");
+                db.put_bool("recording", false)?;
             }
         }
+
+        return Ok(());
     }
 
     let output = Command::new("slurp")
@@ -85,36 +211,102 @@ fn main() {
         .expect("failed to execute process");
 
     let dimensions = String::from_utf8_lossy(&output.stdout);
-    if dimensions.trim().len() < 1 {
-        return;
+    if dimensions.trim().is_empty() {
+        return Ok(());
     }
 
     let since_the_epoch = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .expect("Time went backwards");
 
-    db.put_bool("recording", true);
-    db.put_i64("recording:start", since_the_epoch.as_secs() as i64);
-    db.put_i64("recording:pid", since_the_epoch.as_secs() as i64);
+    if !claim_start(&db, since_the_epoch.as_secs() as i64)? {
+        return Ok(());
+    }
 
-    let mut child = Command::new("wf-recorder")
-        .arg("-g")
-        .arg(format!("{}", dimensions))
-        .arg("-x")
-        .arg("yuv420p")
-        .arg("-f")
-        .arg(format!(
-            "{}/{}.mp4",
-            xdg_user::videos().unwrap().unwrap().to_string_lossy(),
-            since_the_epoch.as_secs()
-        ))
-        .spawn()
-        .expect("failed to execute process");
+    let mut child = match spawn_recorder(&dimensions, since_the_epoch.as_secs()) {
+        Ok(child) => child,
+        Err(e) => {
+            // Release the claim, otherwise no later start gets past it
+            db.put_bool("recording", false)?;
+            return Err(e);
+        }
+    };
 
     let pid = child.id() as i64;
-    db.put_i64("recording:pid", pid);
+    if let Err(e) = recording.put_i64("pid", pid) {
+        // Without the pid `--stop` could never end this recording
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(e.into());
+    }
+
+    let status = child.wait();
+    db.put_bool("recording", false)?;
+    status?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib::kv::MemoryStore;
+    use std::rc::Rc;
+
+    fn kv() -> Kv {
+        Kv::with_store(Rc::new(MemoryStore::new())).unwrap()
+    }
+
+    #[test]
+    fn start_and_stop() {
+        let db = kv();
+        assert_eq!(elapsed(&db).unwrap(), None);
+        assert_eq!(claim_stop(&db).unwrap(), Stop::Idle);
 
-    child.wait().expect("failed to wait on child");
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+            - 65;
+        assert!(claim_start(&db, start).unwrap());
+        assert!(!claim_start(&db, start + 1).unwrap());
+        assert!((65..=66).contains(&elapsed(&db).unwrap().unwrap()));
 
-    db.put_bool("recording", false);
+        db.namespace("recording").put_i64("pid", 4242).unwrap();
+        assert_eq!(claim_stop(&db).unwrap(), Stop::Running(4242));
+        assert_eq!(claim_stop(&db).unwrap(), Stop::Starting);
+
+        // Once wf-recorder exits another recording may start
+        db.put_bool("recording", false).unwrap();
+        assert_eq!(elapsed(&db).unwrap(), None);
+        assert!(claim_start(&db, start).unwrap());
+    }
+
+    #[test]
+    fn stop_while_starting() {
+        let db = kv();
+        db.namespace("recording").put_i64("pid", 4242).unwrap();
+        db.put_bool("recording", false).unwrap();
+
+        // A stale pid from the last recording must not be killed
+        assert!(claim_start(&db, 1).unwrap());
+        assert_eq!(claim_stop(&db).unwrap(), Stop::Starting);
+        assert!(db.get_bool("recording").unwrap());
+
+        db.namespace("recording").put_i64("pid", 4343).unwrap();
+        assert_eq!(claim_stop(&db).unwrap(), Stop::Running(4343));
+    }
+
+    #[test]
+    fn broken_state() {
+        // The listener shows these as the error state
+        let db = kv();
+        db.put_bool("recording", true).unwrap();
+        assert!(matches!(elapsed(&db), Err(KvError::NotFound)));
+
+        db.namespace("recording")
+            .put_string("start", "now")
+            .unwrap();
+        assert!(matches!(elapsed(&db), Err(KvError::TypeMismatch(_))));
+    }
 }
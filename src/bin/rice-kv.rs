@@ -0,0 +1,193 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use core_affinity::CoreId;
+use futures::StreamExt;
+use lib::kv::{Backend, Change, Entry, Kv, KvError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::time::Duration;
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Cmd,
+
+    /// Which store to operate on
+    #[arg(long, value_enum, default_value_t = Backend::Sqlite)]
+    backend: Backend,
+}
+
+#[derive(Subcommand, Debug)]
+enum Cmd {
+    /// Print the value of a key
+    Get {
+        key: String,
+
+        #[arg(long = "type", value_enum, default_value_t = Type::Text)]
+        ty: Type,
+    },
+
+    /// Store a value under a key
+    Set {
+        key: String,
+
+        value: String,
+
+        #[arg(long = "type", value_enum, default_value_t = Type::Text)]
+        ty: Type,
+
+        /// Expire the key after this many seconds
+        #[arg(long)]
+        ttl: Option<u64>,
+    },
+
+    /// Delete a key
+    Del { key: String },
+
+    /// List keys, optionally only those starting with a prefix
+    List { prefix: Option<String> },
+
+    /// Print every change to keys starting with a prefix
+    Watch { prefix: Option<String> },
+
+    /// Dump keys and values as a JSON object, with `expires_at` (unix seconds) for keys with a TTL
+    Export { prefix: Option<String> },
+
+    /// Load a JSON object produced by `export` from stdin. Keys keep their expiry time,
+    /// so those that expired in the meantime stay missing
+    Import,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Type {
+    Bool,
+    Text,
+    I64,
+    F64,
+    Json,
+}
+
+/// A key as `export` writes it.
+#[derive(Serialize, Deserialize)]
+struct Exported {
+    #[serde(flatten)]
+    entry: Entry,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<i64>,
+}
+
+fn get(db: &Kv, key: &str, ty: Type) -> Result<String, KvError> {
+    Ok(match ty {
+        Type::Bool => db.get_bool(key)?.to_string(),
+        Type::Text => db.get_string(key)?,
+        Type::I64 => db.get_i64(key)?.to_string(),
+        Type::F64 => db.get_f64(key)?.to_string(),
+        Type::Json => db.get::<serde_json::Value>(key)?.to_string(),
+    })
+}
+
+fn set(
+    db: &Kv,
+    key: &str,
+    value: &str,
+    ty: Type,
+    ttl: Option<Duration>,
+) -> Result<(), Box<dyn Error>> {
+    match (ty, ttl) {
+        (Type::Bool, None) => db.put_bool(key, value.parse()?)?,
+        (Type::Bool, Some(ttl)) => db.put_bool_with_ttl(key, value.parse()?, ttl)?,
+        (Type::Text, None) => db.put_string(key, value)?,
+        (Type::Text, Some(ttl)) => db.put_string_with_ttl(key, value, ttl)?,
+        (Type::I64, None) => db.put_i64(key, value.parse()?)?,
+        (Type::I64, Some(ttl)) => db.put_i64_with_ttl(key, value.parse()?, ttl)?,
+        (Type::F64, None) => db.put_f64(key, value.parse()?)?,
+        (Type::F64, Some(ttl)) => db.put_f64_with_ttl(key, value.parse()?, ttl)?,
+        (Type::Json, None) => db.put(key, &serde_json::from_str::<serde_json::Value>(value)?)?,
+        (Type::Json, Some(ttl)) => {
+            db.put_with_ttl(key, &serde_json::from_str::<serde_json::Value>(value)?, ttl)?
+        }
+    }
+
+    Ok(())
+}
+
+fn export(db: &Kv, prefix: &str) -> Result<BTreeMap<String, Exported>, KvError> {
+    db.transaction(|tx| {
+        let mut entries = BTreeMap::new();
+        for (key, entry) in tx.scan(prefix)? {
+            let expires_at = match tx.expires_at(&key) {
+                Ok(expires_at) => expires_at,
+                // Expired since the scan
+                Err(KvError::NotFound) => continue,
+                Err(e) => return Err(e),
+            };
+            entries.insert(key, Exported { entry, expires_at });
+        }
+        Ok(entries)
+    })
+}
+
+fn import(db: &Kv, entries: BTreeMap<String, Exported>) -> Result<(), KvError> {
+    db.transaction(|tx| {
+        for (key, exported) in entries {
+            tx.put_entry(&key, exported.entry, exported.expires_at)?;
+        }
+
+        Ok(())
+    })
+}
+
+async fn watch(db: &Kv, prefix: &str) -> Result<(), Box<dyn Error>> {
+    let mut changes = std::pin::pin!(db.watch(prefix)?);
+
+    while let Some(change) = changes.next().await {
+        match change? {
+            Change::Put(key) => println!("put {}", key),
+            Change::Del(key) => println!("del {}", key),
+        }
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    // Pin to 0 CPU (E-Core)
+    core_affinity::set_for_current(CoreId { id: 0 });
+
+    let args = Args::parse();
+    let db = Kv::open(args.backend)?;
+
+    match args.command {
+        Cmd::Get { key, ty } => println!("{}", get(&db, &key, ty)?),
+        Cmd::Set {
+            key,
+            value,
+            ty,
+            ttl,
+        } => set(&db, &key, &value, ty, ttl.map(Duration::from_secs))?,
+        Cmd::Del { key } => db.del(&key)?,
+        Cmd::List { prefix } => {
+            for key in db.keys(prefix.as_deref().unwrap_or_default())? {
+                println!("{}", key);
+            }
+        }
+        Cmd::Watch { prefix } => {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(watch(&db, prefix.as_deref().unwrap_or_default()))?;
+        }
+        Cmd::Export { prefix } => {
+            let entries = export(&db, prefix.as_deref().unwrap_or_default())?;
+            println!("{}", serde_json::to_string_pretty(&entries)?);
+        }
+        Cmd::Import => {
+            let entries = serde_json::from_reader(std::io::stdin().lock())?;
+            import(&db, entries)?;
+        }
+    }
+
+    Ok(())
+}
@@ -1,66 +1,211 @@
+use clap::Parser;
 use core_affinity::CoreId;
-use lib::pulse::is_output_muted;
-use lib::types::Response;
+use lib::config::{Config, Module};
+use lib::emit::{Emitter, OutputArgs};
+use lib::pulse::{self, Device, EventKind, Facility, Pulse, Sink};
+use lib::template::Vars;
 use lib::utils::process_signals;
 use std::error::Error;
-use std::process::Stdio;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::{Child, Command};
 
-fn output(muted: bool) {
-    let mut text = "";
-    let mut class = "not-muted";
+const VARIABLES: &[&str] = &["volume", "level", "kind", "device", "port"];
+const HELP: &str = "Format variables: {icon}, {state} (muted, bluetooth, hdmi, headphones, low, \
+                    medium, high, over-amplified), {volume} in percent, {level}, {kind}, \
+                    {device}, {port}";
 
-    if muted {
-        text = "󰝟";
-        class = "muted";
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None, after_help = HELP)]
+struct Args {
+    /// Show the volume next to the icon, with a class per volume level instead of per output
+    #[arg(long, default_value_t = false)]
+    percentage: bool,
+
+    /// Toggle mute and exit
+    #[arg(long, default_value_t = false, conflicts_with_all = ["up", "down"])]
+    toggle: bool,
+
+    /// Raise the volume by this many percent and exit
+    #[arg(long, value_name = "STEP", conflicts_with = "down")]
+    up: Option<u32>,
+
+    /// Lower the volume by this many percent and exit
+    #[arg(long, value_name = "STEP")]
+    down: Option<u32>,
+
+    /// Never raise the volume above this percentage
+    #[arg(long, value_name = "PERCENT", default_value_t = 100)]
+    max: u32,
+
+    /// Make the next sink the default and exit
+    #[arg(long, default_value_t = false, conflicts_with_all = ["menu", "select"])]
+    next: bool,
+
+    /// Print the sinks to pick from, one per line, for a dmenu-style launcher
+    #[arg(long, default_value_t = false, conflicts_with = "select")]
+    menu: bool,
+
+    /// Make this sink the default and exit. Takes a name, an index or a line printed by --menu
+    #[arg(long, value_name = "SINK")]
+    select: Option<String>,
+
+    /// With --next or --select, move existing streams to the new default
+    #[arg(long, default_value_t = false)]
+    move_streams: bool,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+/// Where the sound goes, as a CSS class: bluetooth, hdmi, headphones or speaker.
+fn kind(sink: &Sink) -> &'static str {
+    let port = sink.active_port();
+    let port_kind = port.map(|p| p.kind.as_str()).unwrap_or_default();
+    let port_name = port.map(|p| p.name.as_str()).unwrap_or_default();
+
+    if sink.bus() == Some("bluetooth") || sink.name.starts_with("bluez_") {
+        "bluetooth"
+    } else if port_kind == "HDMI" || port_name.contains("hdmi") || sink.form_factor() == Some("tv")
+    {
+        "hdmi"
+    } else if matches!(port_kind, "Headphones" | "Headset")
+        || port_name.contains("headphones")
+        || matches!(sink.form_factor(), Some("headphone" | "headset"))
+    {
+        "headphones"
+    } else {
+        "speaker"
     }
+}
+
+fn output(emitter: &mut dyn Emitter, module: &Module, sink: Option<&Sink>, percentage: bool) {
+    let format = if percentage {
+        "{icon} {volume}%"
+    } else {
+        "{icon}"
+    };
+    let tooltip = "{device}[\n{port}]";
+
+    let Some(sink) = sink else {
+        let response = module.response("muted", format, tooltip, Vars::new());
+        emitter.print(&response.alt("muted"));
+        return;
+    };
 
-    println!(
-        "{}",
-        serde_json::to_string(&Response { class, text }).unwrap()
-    );
+    let kind = kind(sink);
+    let level = sink.volume.level();
+
+    // Plain speakers show the level, other outputs show what they are
+    let state = match kind {
+        _ if sink.mute => "muted",
+        "speaker" => level,
+        kind => kind,
+    };
+
+    let vars = Vars::new()
+        .set("volume", sink.volume.percent())
+        .set("level", level)
+        .set("kind", kind)
+        .set("device", sink.description.as_str())
+        .set(
+            "port",
+            sink.active_port().map_or("", |p| p.description.as_str()),
+        );
+
+    // Both, so that styles can key on the device, the level, or the two together
+    let response = module
+        .response(state, format, tooltip, vars)
+        .alt(if sink.mute { "muted" } else { kind })
+        .percentage(sink.volume.percent())
+        .class(kind)
+        .class(level);
+    emitter.print(&response);
 }
 
-async fn process_lines(child: &mut Child) -> Result<(), Box<dyn Error>> {
-    let stdout = child
-        .stdout
-        .take()
-        .expect("Child process stdout is not available");
-    let mut lines = BufReader::new(stdout).lines();
+async fn process_events(
+    pulse: &Pulse,
+    emitter: &mut dyn Emitter,
+    module: &Module,
+    percentage: bool,
+) -> Result<(), Box<dyn Error>> {
+    // Subscribe first so that nothing happening in between is missed
+    let events = pulse.subscribe()?;
+
+    let mut sink = pulse.output_device().await?;
+    output(emitter, module, sink.as_ref(), percentage);
 
-    while let Some(line) = lines.next_line().await? {
-        if !line.contains("Event 'change' on sink") {
-            continue;
+    pulse::handle_events(events, emitter, async |emitter, event| {
+        let refresh = match (event.facility, event.kind) {
+            // The default sink may have switched
+            (Facility::Server, _) | (Facility::Sink, EventKind::New | EventKind::Remove) => true,
+            // Only changes to the default sink itself matter
+            (Facility::Sink, EventKind::Change) => sink
+                .as_ref()
+                .is_none_or(|sink| event.index == Some(sink.index)),
+            _ => false,
+        };
+        if refresh {
+            sink = pulse.output_device().await?;
+            output(emitter, module, sink.as_ref(), percentage);
         }
+        Ok(())
+    })
+    .await
+}
+
+async fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    let pulse = Pulse::new();
 
-        output(is_output_muted().await?);
+    // One-shot actions for waybar's on-click and on-scroll handlers
+    let device = Device::Sink;
+    if args.toggle {
+        return pulse.toggle_mute(device, device.default_name()).await;
+    }
+    if let Some(step) = args.up {
+        pulse
+            .change_volume(device, device.default_name(), step as i32, args.max)
+            .await?;
+        return Ok(());
+    }
+    if let Some(step) = args.down {
+        pulse
+            .change_volume(device, device.default_name(), -(step as i32), args.max)
+            .await?;
+        return Ok(());
     }
 
-    Ok(())
-}
+    // Switching the default, for the device picker
+    if args.next || args.menu || args.select.is_some() {
+        let sinks = pulse.list_sinks().await?;
 
-async fn run() -> Result<(), Box<dyn Error>> {
-    output(is_output_muted().await?);
+        if args.menu {
+            for sink in &sinks {
+                println!("{}", pulse::menu_entry(sink));
+            }
+            return Ok(());
+        }
 
-    let mut child = Command::new("pactl")
-        .arg("subscribe")
-        .stdout(Stdio::piped())
-        .spawn()?;
+        let choice = match args.select {
+            Some(choice) => pulse::find(&sinks, &choice).ok_or("No such sink")?,
+            None => pulse::next(&sinks, &pulse.default_sink().await?).ok_or("No sinks found")?,
+        };
+        return pulse
+            .set_default_sink(&choice.name, args.move_streams)
+            .await;
+    }
 
+    let module = args
+        .output
+        .module(&Config::load()?, env!("CARGO_BIN_NAME"), VARIABLES)?;
+    let mut emitter = args.output.emitter(env!("CARGO_BIN_NAME"));
     tokio::select! {
         result = process_signals() => result,
-        result = process_lines(&mut child) => result,
-    }?;
-
-    // Ensure the child process is killed
-    let _ = child.kill().await;
-
-    Ok(())
+        result = process_events(&pulse, &mut *emitter, &module, args.percentage) => result,
+    }
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    Ok(tokio::runtime::Builder::new_current_thread()
+    let args = Args::parse();
+
+    tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .worker_threads(1)
         .on_thread_start(|| {
@@ -68,5 +213,5 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         })
         .build()
         .unwrap()
-        .block_on(run())?)
+        .block_on(run(args))
 }
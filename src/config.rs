@@ -0,0 +1,310 @@
+use crate::template::{Template, TemplateError, Vars};
+use crate::types::Response;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const DEFAULTS: &str = include_str!("config/defaults.toml");
+
+#[derive(Debug)]
+pub enum ConfigError {
+    NoDirectory(xdg::BaseDirectoriesError),
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::NoDirectory(e) => write!(f, "no config directory found: {}", e),
+            ConfigError::Io(path, e) => write!(f, "failed to read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config {}: {}", path.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::NoDirectory(e) => Some(e),
+            ConfigError::Io(_, e) => Some(e),
+            ConfigError::Parse(_, e) => Some(e),
+        }
+    }
+}
+
+/// What a module shows in one of its states, unset fields keep the default.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct State {
+    /// The `{icon}` variable
+    pub icon: Option<String>,
+    /// Template for the text in this state, instead of `--format`
+    #[serde(default, deserialize_with = "template")]
+    pub text: Option<Template>,
+    /// One class or a list of them
+    #[serde(default, deserialize_with = "classes")]
+    pub class: Option<Vec<String>>,
+    /// Template for the tooltip in this state, instead of `--tooltip-format`
+    #[serde(default, deserialize_with = "template")]
+    pub tooltip: Option<Template>,
+}
+
+fn classes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Classes {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(Some(match Classes::deserialize(deserializer)? {
+        Classes::One(class) => vec![class],
+        Classes::Many(classes) => classes,
+    }))
+}
+
+fn template<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Template>, D::Error> {
+    let source = String::deserialize(deserializer)?;
+    Template::parse(&source)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+static UNSET: State = State {
+    icon: None,
+    text: None,
+    class: None,
+    tooltip: None,
+};
+
+impl State {
+    pub fn icon(&self) -> &str {
+        self.icon.as_deref().unwrap_or_default()
+    }
+
+    fn merge(&mut self, other: State) {
+        self.icon = other.icon.or(self.icon.take());
+        self.text = other.text.or(self.text.take());
+        self.class = other.class.or(self.class.take());
+        self.tooltip = other.tooltip.or(self.tooltip.take());
+    }
+}
+
+/// The states of one binary, by name.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct Module {
+    states: BTreeMap<String, State>,
+    #[serde(skip)]
+    format: Option<Template>,
+    #[serde(skip)]
+    tooltip_format: Option<Template>,
+}
+
+/// Every module sets these, besides its own variables.
+const VARIABLES: &[&str] = &["icon", "state"];
+
+impl Module {
+    /// A state the config doesn't mention shows nothing special.
+    pub fn state(&self, name: &str) -> &State {
+        self.states.get(name).unwrap_or(&UNSET)
+    }
+
+    /// Templates for the states that have no `text` or `tooltip` of their own,
+    /// instead of the module's built-in ones.
+    pub fn with_formats(mut self, format: Option<Template>, tooltip: Option<Template>) -> Module {
+        self.format = format;
+        self.tooltip_format = tooltip;
+        self
+    }
+
+    /// Fails on templates using variables other than `variables`, which the module sets.
+    pub fn check(&self, variables: &[&str]) -> Result<(), TemplateError> {
+        let known = [VARIABLES, variables].concat();
+        let states = self.states.values();
+        states
+            .flat_map(|state| [&state.text, &state.tooltip])
+            .chain([&self.format, &self.tooltip_format])
+            .flatten()
+            .try_for_each(|template| template.check(&known))
+    }
+
+    /// Renders the text and tooltip of `state`, falling back to `--format` and
+    /// `--tooltip-format`, then to the given built-in ones, and adds its classes.
+    /// An empty tooltip is left out.
+    pub fn response(
+        &self,
+        state: &str,
+        format: &str,
+        tooltip_format: &str,
+        vars: Vars,
+    ) -> Response<'static> {
+        let name = state;
+        let state = self.state(name);
+        let vars = vars.set("icon", state.icon()).set("state", name);
+        let render = |template: Option<&Template>, default: &str| match template {
+            Some(template) => template.render(&vars),
+            None => Template::parse(default)
+                .expect("Built-in format is invalid")
+                .render(&vars),
+        };
+
+        let text = render(state.text.as_ref().or(self.format.as_ref()), format);
+        let tooltip = render(
+            state.tooltip.as_ref().or(self.tooltip_format.as_ref()),
+            tooltip_format,
+        );
+
+        let mut response = Response::new(text);
+        if !tooltip.is_empty() {
+            response = response.tooltip(tooltip);
+        }
+        for class in state.class.iter().flatten() {
+            response = response.class(class.clone());
+        }
+        response
+    }
+}
+
+/// Icons, texts, classes and tooltips of every module, by binary name.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct Config {
+    modules: BTreeMap<String, Module>,
+}
+
+impl Config {
+    /// The built-in defaults with `$XDG_CONFIG_HOME/riceutils/config.toml` on top, if it exists.
+    pub fn load() -> Result<Config, ConfigError> {
+        let dir =
+            xdg::BaseDirectories::with_prefix("riceutils").map_err(ConfigError::NoDirectory)?;
+        let mut config = Config::defaults();
+        if let Some(path) = dir.find_config_file("config.toml") {
+            config.merge(Config::open(&path)?);
+        }
+        Ok(config)
+    }
+
+    pub fn defaults() -> Config {
+        toml::from_str(DEFAULTS).expect("Built-in config is invalid")
+    }
+
+    /// Only what's in the file, without the defaults.
+    pub fn open(path: &Path) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    /// Overrides with whatever `other` sets, field by field.
+    pub fn merge(&mut self, other: Config) {
+        for (name, module) in other.modules {
+            let ours = self.modules.entry(name).or_default();
+            for (name, state) in module.states {
+                ours.states.entry(name).or_default().merge(state);
+            }
+        }
+    }
+
+    pub fn module(&self, name: &str) -> Module {
+        self.modules.get(name).cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let bulb = Config::defaults().module("bulb");
+        assert_eq!(bulb.state("off").icon(), "󰹏");
+        assert_eq!(
+            serde_json::to_string(&bulb.response("bg-on", "{icon}", "", Vars::new())).unwrap(),
+            "{\"class\":\"bulb-bg-on\",\"text\":\"󱩐\"}"
+        );
+        assert_eq!(Config::defaults().module("nothing").state("on"), &UNSET);
+
+        let mic = Config::defaults().module("mic");
+        assert_eq!(
+            serde_json::to_string(&mic.response("muted-in-use", "{icon}", "", Vars::new()))
+                .unwrap(),
+            "{\"class\":[\"muted\",\"in-use\"],\"text\":\"\"}"
+        );
+    }
+
+    #[test]
+    fn example() {
+        let mut config = Config::defaults();
+        config.merge(toml::from_str(include_str!("../examples/config.toml")).unwrap());
+
+        let mic = config.module("mic");
+        assert_eq!(
+            mic.response("not-muted", "{icon}", "", Vars::new()).text,
+            ""
+        );
+        assert_eq!(
+            mic.response("in-use", "{icon}", "", Vars::new()).text,
+            "\u{f130}"
+        );
+        assert_eq!(
+            config.module("card-profile").state("input").icon(),
+            "\u{f130}"
+        );
+        assert_eq!(
+            Config::defaults()
+                .module("card-profile")
+                .state("input")
+                .icon(),
+            ""
+        );
+    }
+
+    #[test]
+    fn merge() {
+        let mut config = Config::defaults();
+        config.merge(
+            toml::from_str(
+                "[bulb.off]\nicon = \"off\"\n\n[bulb.on]\nclass = [\"bulb-on\", \"bright\"]\n",
+            )
+            .unwrap(),
+        );
+
+        let bulb = config.module("bulb");
+        assert_eq!(bulb.state("off").icon(), "off");
+        assert_eq!(bulb.state("off").class, Some(vec!["bulb-off".to_string()]));
+        assert_eq!(bulb.state("on").icon(), "");
+        assert_eq!(
+            bulb.state("on").class,
+            Some(vec!["bulb-on".to_string(), "bright".to_string()])
+        );
+    }
+
+    #[test]
+    fn formats() {
+        let mut config = Config::defaults();
+        config.merge(toml::from_str("[battery.power-idle]\ntext = \"idle\"\n").unwrap());
+        let battery = config
+            .module("battery")
+            .with_formats(Some("{capacity}%".parse().unwrap()), None);
+
+        let vars = || Vars::new().set("capacity", 42u32);
+        assert_eq!(
+            battery.response("capacity", "{icon}", "", vars()).text,
+            "42%"
+        );
+        assert_eq!(battery.response("power-idle", "", "", vars()).text, "idle");
+        assert_eq!(battery.response("power-err", "", "", vars()).text, "ERR");
+        assert!(battery.check(&["capacity"]).is_ok());
+        assert!(battery.check(&["power"]).is_err());
+    }
+
+    #[test]
+    fn unknown_fields() {
+        assert!(toml::from_str::<Config>("[bulb.off]\ncolour = \"red\"\n").is_err());
+        assert!(toml::from_str::<Config>("[bulb.off]\ntext = \"{icon\"\n").is_err());
+    }
+}
@@ -0,0 +1,323 @@
+use crate::config::{Config, Module};
+use crate::template::{Template, TemplateError};
+use crate::types::Response;
+use clap::ValueEnum;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+mod dedup;
+mod eww;
+mod i3bar;
+mod plain;
+mod polybar;
+mod waybar;
+
+pub use dedup::Dedup;
+pub use eww::Eww;
+pub use i3bar::I3bar;
+pub use plain::Plain;
+pub use polybar::Polybar;
+pub use waybar::Waybar;
+
+/// Writes `Response`s in the protocol a bar reads.
+pub trait Emitter {
+    /// Writes one update, and whatever the protocol needs before the first one.
+    fn emit(&mut self, out: &mut dyn Write, response: &Response) -> io::Result<()>;
+
+    /// When the last update should be written again even though nothing changed.
+    fn due(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Writes the last update again, once `due` has passed.
+    fn repeat(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Emits to stdout and flushes, panicking like `println!` when stdout is gone.
+    fn print(&mut self, response: &Response) {
+        to_stdout(|out| self.emit(out, response));
+    }
+
+    /// Repeats to stdout like `print`.
+    fn print_again(&mut self) {
+        to_stdout(|out| self.repeat(out));
+    }
+}
+
+fn to_stdout(write: impl FnOnce(&mut dyn Write) -> io::Result<()>) {
+    let mut out = io::stdout().lock();
+    if let Err(e) = write(&mut out).and_then(|_| out.flush()) {
+        panic!("failed printing to stdout: {}", e);
+    }
+}
+
+impl<E: Emitter + ?Sized> Emitter for Box<E> {
+    fn emit(&mut self, out: &mut dyn Write, response: &Response) -> io::Result<()> {
+        (**self).emit(out, response)
+    }
+
+    fn due(&self) -> Option<Instant> {
+        (**self).due()
+    }
+
+    fn repeat(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        (**self).repeat(out)
+    }
+}
+
+/// Sleeps until an emitter's `due`, or forever when nothing is due, so that
+/// event loops can wait on it next to their events and call `print_again`.
+pub async fn sleep_until(due: Option<Instant>) {
+    match due {
+        Some(due) => tokio::time::sleep_until(due.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Which bar a binary writes for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum FormatBackend {
+    /// One JSON object per line, for waybar's custom modules
+    #[default]
+    Waybar,
+    /// The i3bar protocol, as read by i3bar and swaybar
+    I3bar,
+    /// One line of text with polybar's formatting tags
+    Polybar,
+    /// One JSON object per line with every field present, for eww's `deflisten`
+    Eww,
+    /// The bare text
+    Plain,
+}
+
+impl FormatBackend {
+    /// `name` identifies the module to bars that show several in one stream.
+    pub fn emitter(self, name: &str) -> Box<dyn Emitter> {
+        match self {
+            FormatBackend::Waybar => Box::new(Waybar),
+            FormatBackend::I3bar => Box::new(I3bar::new(name)),
+            FormatBackend::Polybar => Box::new(Polybar::new()),
+            FormatBackend::Eww => Box::new(Eww),
+            FormatBackend::Plain => Box::new(Plain),
+        }
+    }
+}
+
+/// The flags every binary shares for choosing its output.
+#[derive(clap::Args, Debug)]
+pub struct OutputArgs {
+    /// Which bar the output is written for
+    #[arg(long, value_enum, default_value_t = FormatBackend::Waybar)]
+    pub format_backend: FormatBackend,
+
+    /// Color polybar output with this class in COLOR, e.g. muted=#808080. Can be repeated,
+    /// the first one matching wins
+    #[arg(long, value_name = "CLASS=COLOR", value_parser = class_color)]
+    pub polybar_color: Vec<(String, String)>,
+
+    /// Repeat an unchanged line after this many seconds, otherwise it's only printed once
+    #[arg(long, value_name = "SECS")]
+    pub heartbeat: Option<u64>,
+
+    /// Template for the text, e.g. "{icon}[ {volume}%]", see below for the variables
+    #[arg(long, value_name = "TEMPLATE")]
+    pub format: Option<Template>,
+
+    /// Template for the tooltip
+    #[arg(long, value_name = "TEMPLATE")]
+    pub tooltip_format: Option<Template>,
+}
+
+impl OutputArgs {
+    /// The chosen backend, skipping lines that didn't change.
+    pub fn emitter(&self, name: &str) -> Box<dyn Emitter> {
+        let backend = match self.format_backend {
+            FormatBackend::Polybar => Box::new(
+                self.polybar_color
+                    .iter()
+                    .fold(Polybar::new(), |polybar, (class, color)| {
+                        polybar.with_color(class, color)
+                    }),
+            ),
+            backend => backend.emitter(name),
+        };
+        Box::new(Dedup::new(backend, self.heartbeat.map(Duration::from_secs)))
+    }
+
+    /// `name`'s part of `config` with the templates given, checked against
+    /// the `variables` the binary sets.
+    pub fn module(
+        &self,
+        config: &Config,
+        name: &str,
+        variables: &[&str],
+    ) -> Result<Module, TemplateError> {
+        let module = config
+            .module(name)
+            .with_formats(self.format.clone(), self.tooltip_format.clone());
+        module.check(variables)?;
+        Ok(module)
+    }
+}
+
+fn class_color(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((class, color)) if !class.is_empty() && !color.is_empty() => {
+            Ok((class.to_string(), color.to_string()))
+        }
+        _ => Err(format!("expected CLASS=COLOR, got {:?}", arg)),
+    }
+}
+
+/// Text based bars take a line per update, so a newline would split it.
+fn one_line(text: &str) -> String {
+    text.replace('\n', " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn emitted(emitter: &mut dyn Emitter, responses: &[Response]) -> String {
+        let mut out = Vec::new();
+        for response in responses {
+            emitter.emit(&mut out, response).unwrap();
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    fn volume() -> Response<'static> {
+        Response::new("󰖀 65%")
+            .class("speaker")
+            .class("medium")
+            .tooltip("Built-in Audio\nSpeakers")
+            .alt("speaker")
+            .percentage(65)
+    }
+
+    #[test]
+    fn waybar() {
+        assert_eq!(
+            emitted(&mut Waybar, &[Response::new("ERR").class("recording-err")]),
+            "{\"class\":\"recording-err\",\"text\":\"ERR\"}\n"
+        );
+        assert_eq!(
+            emitted(&mut Waybar, &[volume()]),
+            "{\"class\":[\"speaker\",\"medium\"],\"text\":\"󰖀 65%\",\
+             \"tooltip\":\"Built-in Audio\\nSpeakers\",\"alt\":\"speaker\",\"percentage\":65}\n"
+        );
+    }
+
+    #[test]
+    fn i3bar() {
+        let out = emitted(&mut I3bar::new("speaker"), &[volume(), Response::new("󰝟")]);
+        assert_eq!(
+            out,
+            "{\"version\":1}\n[\n\
+             [{\"full_text\":\"󰖀 65%\",\"name\":\"speaker\",\"_class\":[\"speaker\",\"medium\"]}]\n\
+             ,[{\"full_text\":\"󰝟\",\"name\":\"speaker\",\"_class\":[]}]\n"
+        );
+    }
+
+    #[test]
+    fn polybar() {
+        let mut polybar = Polybar::new().with_color("muted", "#808080");
+        assert_eq!(
+            emitted(
+                &mut polybar,
+                &[volume(), Response::new("󰝟").class("speaker").class("muted")]
+            ),
+            "󰖀 65%\n%{F#808080}󰝟%{F-}\n"
+        );
+    }
+
+    #[test]
+    fn polybar_colors() {
+        #[derive(clap::Parser)]
+        struct Args {
+            #[command(flatten)]
+            output: OutputArgs,
+        }
+
+        let args = <Args as clap::Parser>::try_parse_from([
+            "speaker",
+            "--format-backend=polybar",
+            "--polybar-color=muted=#808080",
+        ])
+        .unwrap();
+        let mut emitter = args.output.emitter("speaker");
+        assert_eq!(
+            emitted(&mut emitter, &[Response::new("󰝟").class("muted")]),
+            "%{F#808080}󰝟%{F-}\n"
+        );
+
+        assert!(
+            <Args as clap::Parser>::try_parse_from(["speaker", "--polybar-color=muted"]).is_err()
+        );
+    }
+
+    #[test]
+    fn eww() {
+        assert_eq!(
+            emitted(&mut Eww, &[volume(), Response::new("")]),
+            "{\"text\":\"󰖀 65%\",\"class\":\"speaker medium\",\
+             \"tooltip\":\"Built-in Audio\\nSpeakers\",\"alt\":\"speaker\",\"percentage\":65}\n\
+             {\"text\":\"\",\"class\":\"\",\"tooltip\":\"\",\"alt\":\"\",\"percentage\":null}\n"
+        );
+    }
+
+    #[test]
+    fn dedup() {
+        let off = Response::new("󰹏").class("bulb-off");
+        let on = Response::new("").class("bulb-on");
+
+        let mut dedup = Dedup::new(Plain, None);
+        assert_eq!(
+            emitted(
+                &mut dedup,
+                &[off.clone(), off.clone(), on.clone(), off.clone()]
+            ),
+            "󰹏\n\n󰹏\n"
+        );
+
+        // Only the class changed, which is still a different line for waybar
+        let mut dedup = Dedup::new(Plain, None);
+        assert_eq!(
+            emitted(
+                &mut dedup,
+                &[off.clone(), Response::new("󰹏").class("bulb-bg-on")]
+            ),
+            "󰹏\n󰹏\n"
+        );
+
+        let mut dedup = Dedup::new(Plain, Some(Duration::ZERO));
+        assert_eq!(emitted(&mut dedup, &[off.clone(), off]), "󰹏\n󰹏\n");
+    }
+
+    #[test]
+    fn heartbeat() {
+        let off = Response::new("󰹏").class("bulb-off");
+
+        let mut dedup = Dedup::new(Plain, Some(Duration::from_secs(60)));
+        assert_eq!(dedup.due(), None);
+        emitted(&mut dedup, std::slice::from_ref(&off));
+        assert!(dedup.due().is_some_and(|due| due > Instant::now()));
+
+        let mut out = Vec::new();
+        dedup.repeat(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "󰹏\n");
+
+        let mut dedup = Dedup::new(Plain, None);
+        emitted(&mut dedup, &[off]);
+        assert_eq!(dedup.due(), None);
+    }
+
+    #[test]
+    fn plain() {
+        assert_eq!(
+            emitted(&mut Plain, &[volume(), Response::new("a\nb")]),
+            "󰖀 65%\na b\n"
+        );
+    }
+}
@@ -0,0 +1,56 @@
+use super::Emitter;
+use crate::types::Response;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// Passes a response on only when it differs from the last one written, so
+/// polling modules don't make the bar re-parse and redraw an unchanged line.
+/// With a heartbeat the same line is written again once that much time has
+/// passed, on the next update or when `due`, so that a restarted bar gets one soon.
+#[derive(Debug, Clone)]
+pub struct Dedup<E> {
+    inner: E,
+    heartbeat: Option<Duration>,
+    last: Option<(Response<'static>, Instant)>,
+}
+
+impl<E: Emitter> Dedup<E> {
+    pub fn new(inner: E, heartbeat: Option<Duration>) -> Dedup<E> {
+        Dedup {
+            inner,
+            heartbeat,
+            last: None,
+        }
+    }
+}
+
+impl<E: Emitter> Emitter for Dedup<E> {
+    fn emit(&mut self, out: &mut dyn Write, response: &Response) -> io::Result<()> {
+        if let Some((last, written)) = &self.last
+            && last == response
+            && self
+                .heartbeat
+                .is_none_or(|heartbeat| written.elapsed() < heartbeat)
+        {
+            return Ok(());
+        }
+
+        self.inner.emit(out, response)?;
+        self.last = Some((response.clone().into_owned(), Instant::now()));
+        Ok(())
+    }
+
+    fn due(&self) -> Option<Instant> {
+        let (_, written) = self.last.as_ref()?;
+        Some(*written + self.heartbeat?)
+    }
+
+    fn repeat(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        let Some((last, _)) = self.last.take() else {
+            return Ok(());
+        };
+        self.inner.emit(out, &last)?;
+        self.last = Some((last, Instant::now()));
+        Ok(())
+    }
+}
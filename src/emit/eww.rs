@@ -0,0 +1,33 @@
+use super::Emitter;
+use crate::types::Response;
+use serde::Serialize;
+use std::io::{self, Write};
+
+#[derive(Serialize)]
+struct Widget<'a> {
+    text: &'a str,
+    class: String,
+    tooltip: &'a str,
+    alt: &'a str,
+    percentage: Option<u32>,
+}
+
+/// JSON for eww's `deflisten`. Every key is always present, so that yuck
+/// expressions like `{speaker.tooltip}` never hit a missing one, and the
+/// classes are joined with spaces to go straight into `:class`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Eww;
+
+impl Emitter for Eww {
+    fn emit(&mut self, out: &mut dyn Write, response: &Response) -> io::Result<()> {
+        let widget = Widget {
+            text: &response.text,
+            class: response.class.join(" "),
+            tooltip: response.tooltip.as_deref().unwrap_or_default(),
+            alt: response.alt.as_deref().unwrap_or_default(),
+            percentage: response.percentage,
+        };
+        serde_json::to_writer(&mut *out, &widget)?;
+        writeln!(out)
+    }
+}
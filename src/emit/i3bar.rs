@@ -0,0 +1,52 @@
+use super::Emitter;
+use crate::types::Response;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::io::{self, Write};
+
+#[derive(Serialize)]
+struct Block<'a> {
+    full_text: &'a str,
+    name: &'a str,
+    // Bars ignore keys starting with an underscore, scripts reading the stream can use them
+    #[serde(rename = "_class")]
+    class: &'a [Cow<'a, str>],
+}
+
+/// The i3bar protocol, see swaybar-protocol(7): a header, then an endless array
+/// holding one array of blocks per update. Each update is this module's only block.
+#[derive(Debug, Clone)]
+pub struct I3bar {
+    name: String,
+    started: bool,
+}
+
+impl I3bar {
+    pub fn new(name: &str) -> I3bar {
+        I3bar {
+            name: name.to_string(),
+            started: false,
+        }
+    }
+}
+
+impl Emitter for I3bar {
+    fn emit(&mut self, out: &mut dyn Write, response: &Response) -> io::Result<()> {
+        if self.started {
+            write!(out, ",")?;
+        } else {
+            writeln!(out, "{{\"version\":1}}")?;
+            writeln!(out, "[")?;
+            self.started = true;
+        }
+
+        let text = super::one_line(&response.text);
+        let block = Block {
+            full_text: &text,
+            name: &self.name,
+            class: &response.class,
+        };
+        serde_json::to_writer(&mut *out, &[block])?;
+        writeln!(out)
+    }
+}
@@ -0,0 +1,13 @@
+use super::Emitter;
+use crate::types::Response;
+use std::io::{self, Write};
+
+/// Only the text, a line per update.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Plain;
+
+impl Emitter for Plain {
+    fn emit(&mut self, out: &mut dyn Write, response: &Response) -> io::Result<()> {
+        writeln!(out, "{}", super::one_line(&response.text))
+    }
+}
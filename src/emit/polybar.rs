@@ -0,0 +1,37 @@
+use super::Emitter;
+use crate::types::Response;
+use std::io::{self, Write};
+
+/// Text for polybar's `custom/script` with `tail = true`. Polybar has no CSS,
+/// so classes can be given a foreground color instead, the first match wins.
+#[derive(Debug, Clone, Default)]
+pub struct Polybar {
+    colors: Vec<(String, String)>,
+}
+
+impl Polybar {
+    pub fn new() -> Polybar {
+        Polybar::default()
+    }
+
+    /// Shows responses with `class` in `color`, such as `#808080`.
+    pub fn with_color(mut self, class: &str, color: &str) -> Polybar {
+        self.colors.push((class.to_string(), color.to_string()));
+        self
+    }
+}
+
+impl Emitter for Polybar {
+    fn emit(&mut self, out: &mut dyn Write, response: &Response) -> io::Result<()> {
+        let text = super::one_line(&response.text);
+        let color = self
+            .colors
+            .iter()
+            .find(|(class, _)| response.class.iter().any(|c| c == class));
+
+        match color {
+            Some((_, color)) => writeln!(out, "%{{F{}}}{}%{{F-}}", color, text),
+            None => writeln!(out, "{}", text),
+        }
+    }
+}
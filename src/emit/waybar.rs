@@ -0,0 +1,14 @@
+use super::Emitter;
+use crate::types::Response;
+use std::io::{self, Write};
+
+/// `Response` as is, see waybar-custom(5).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Waybar;
+
+impl Emitter for Waybar {
+    fn emit(&mut self, out: &mut dyn Write, response: &Response) -> io::Result<()> {
+        serde_json::to_writer(&mut *out, response)?;
+        writeln!(out)
+    }
+}
@@ -1,95 +1,1018 @@
-use rusqlite::Connection;
+use clap::ValueEnum;
+use futures::{Stream, StreamExt};
+use inotify::{EventStream, Inotify, WatchMask};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+mod file;
+mod memory;
+mod sqlite;
+
+pub use file::FileStore;
+pub use memory::MemoryStore;
+pub use sqlite::SqliteStore;
+
+#[derive(Debug)]
+pub enum KvError {
+    NoDirectory(xdg::BaseDirectoriesError),
+    Open(rusqlite::Error),
+    Busy,
+    SchemaMismatch(String),
+    TypeMismatch(String),
+    NotFound,
+    Json(serde_json::Error),
+    Watch(std::io::Error),
+    Io(std::io::Error),
+    Sqlite(rusqlite::Error),
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvError::NoDirectory(e) => write!(f, "no waybar directory found: {}", e),
+            KvError::Open(e) => write!(f, "failed to open the database: {}", e),
+            KvError::Busy => write!(f, "the database is busy or locked"),
+            KvError::SchemaMismatch(msg) => write!(f, "unexpected database schema: {}", msg),
+            KvError::TypeMismatch(msg) => write!(f, "value has a different type: {}", msg),
+            KvError::NotFound => write!(f, "key not found"),
+            KvError::Json(e) => write!(f, "failed to serialize the value: {}", e),
+            KvError::Watch(e) => write!(f, "failed to watch the database: {}", e),
+            KvError::Io(e) => write!(f, "failed to access the store: {}", e),
+            KvError::Sqlite(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for KvError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KvError::NoDirectory(e) => Some(e),
+            KvError::Open(e) | KvError::Sqlite(e) => Some(e),
+            KvError::Json(e) => Some(e),
+            KvError::Watch(e) | KvError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs() as i64
+}
+
+fn expires_at(ttl: Duration) -> Option<i64> {
+    Some(now() + ttl.as_secs() as i64)
+}
+
+/// A single typed value, as handed to `KvStore::put`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Text(String),
+    I64(i64),
+    F64(f64),
+    Json(serde_json::Value),
+}
+
+/// Every typed field of a key. A key written through several `put_*` calls keeps all of them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bool: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub i64: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub f64: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json: Option<serde_json::Value>,
+}
+
+impl Entry {
+    /// The fields that are set, as `put` takes them.
+    fn values(self) -> impl Iterator<Item = Value> {
+        [
+            self.bool.map(Value::Bool),
+            self.text.map(Value::Text),
+            self.i64.map(Value::I64),
+            self.f64.map(Value::F64),
+            self.json.map(Value::Json),
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    fn set(&mut self, value: Value) {
+        match value {
+            Value::Bool(value) => self.bool = Some(value),
+            Value::Text(value) => self.text = Some(value),
+            Value::I64(value) => self.i64 = Some(value),
+            Value::F64(value) => self.f64 = Some(value),
+            Value::Json(value) => self.json = Some(value),
+        }
+    }
+}
+
+/// A Rust type stored in its own typed field of an entry.
+pub trait Column: Sized + PartialEq {
+    const COLUMN: &'static str;
+
+    fn from_entry(entry: Entry) -> Option<Self>;
+
+    fn into_value(self) -> Value;
+}
+
+impl Column for bool {
+    const COLUMN: &'static str = "bool";
+
+    fn from_entry(entry: Entry) -> Option<Self> {
+        entry.bool
+    }
+
+    fn into_value(self) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl Column for String {
+    const COLUMN: &'static str = "text";
+
+    fn from_entry(entry: Entry) -> Option<Self> {
+        entry.text
+    }
+
+    fn into_value(self) -> Value {
+        Value::Text(self)
+    }
+}
+
+impl Column for i64 {
+    const COLUMN: &'static str = "i64";
+
+    fn from_entry(entry: Entry) -> Option<Self> {
+        entry.i64
+    }
+
+    fn into_value(self) -> Value {
+        Value::I64(self)
+    }
+}
+
+impl Column for f64 {
+    const COLUMN: &'static str = "f64";
+
+    fn from_entry(entry: Entry) -> Option<Self> {
+        entry.f64
+    }
+
+    fn into_value(self) -> Value {
+        Value::F64(self)
+    }
+}
+
+/// Storage behind `Kv`. Stores only deal in whole entries and leave the typed API to `Kv`;
+/// all of them must treat entries past their `expires_at` as missing.
+pub trait KvStore {
+    /// Sets one field of `key` and its expiry. Other fields are kept unless the entry had expired.
+    fn put(&self, key: &str, value: Value, expires_at: Option<i64>) -> Result<(), KvError>;
+
+    /// The entry for `key`, or `KvError::NotFound`.
+    fn entry(&self, key: &str) -> Result<Entry, KvError>;
+
+    /// When `key` expires, `None` if never, or `KvError::NotFound`.
+    fn expires_at(&self, key: &str) -> Result<Option<i64>, KvError>;
+
+    fn del(&self, key: &str) -> Result<(), KvError>;
+
+    /// Entries whose key starts with `prefix`, ordered by key.
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Entry)>, KvError>;
+
+    /// Deletes every key starting with `prefix` and returns how many were removed.
+    fn del_prefix(&self, prefix: &str) -> Result<usize, KvError>;
+
+    /// Deletes every expired entry.
+    fn sweep(&self) -> Result<(), KvError>;
+
+    /// Starts a transaction, or returns `false` when one is already running.
+    fn begin(&self) -> Result<bool, KvError>;
+
+    fn commit(&self) -> Result<(), KvError>;
+
+    fn rollback(&self) -> Result<(), KvError>;
+
+    /// The file other processes write to, used by `Kv::watch`. `None` if the store is private.
+    fn path(&self) -> Option<&Path>;
+
+    /// Appends to a time series. Samples live apart from the keys, so `Kv::watch` ignores them.
+    /// A second sample at the same `at` replaces the first.
+    fn record_sample(&self, series: &str, sample: Sample) -> Result<(), KvError>;
+
+    /// Samples of `series` taken at or after `since`, oldest first.
+    fn samples(&self, series: &str, since: i64) -> Result<Vec<Sample>, KvError>;
+
+    /// Averages every series' samples older than `before` into one per `bucket` seconds,
+    /// stamped with the bucket's start. `before` is a multiple of `bucket`.
+    fn compact_samples(&self, before: i64, bucket: i64) -> Result<(), KvError>;
+
+    /// Drops every sample older than `before`.
+    fn prune_samples(&self, before: i64) -> Result<(), KvError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Sample {
+    pub at: i64,
+    pub value: f64,
+}
+
+/// Samples younger than an hour are kept as recorded, then averaged per minute.
+/// After a day they are averaged per 15 minutes, and after a week dropped.
+const SAMPLE_TIERS: &[(i64, i64)] = &[(60 * 60, 60), (24 * 60 * 60, 15 * 60)];
+const SAMPLE_RETENTION: i64 = 7 * 24 * 60 * 60;
+
+/// Which `KvStore` a binary keeps its state in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// `rice.db` in the waybar config directory
+    #[default]
+    Sqlite,
+    /// `rice.json` in the waybar runtime directory, cleared on reboot
+    File,
+    /// Private to the process
+    Memory,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Put(String),
+    Del(String),
+}
+
+type Snapshot = BTreeMap<String, Entry>;
+
+struct Watcher {
+    store: Rc<dyn KvStore>,
+    events: EventStream<[u8; 1024]>,
+    name: OsString,
+    prefix: String,
+    snapshot: Snapshot,
+    pending: VecDeque<Change>,
+}
+
+impl Watcher {
+    async fn next(&mut self) -> Option<Result<Change, KvError>> {
+        loop {
+            if let Some(change) = self.pending.pop_front() {
+                return Some(Ok(change));
+            }
+
+            let event = match self.events.next().await? {
+                Ok(event) => event,
+                Err(e) => return Some(Err(KvError::Watch(e))),
+            };
+
+            // The directory watch also reports the journal/WAL files and unrelated neighbours
+            let ours = event
+                .name
+                .is_some_and(|name| name.as_bytes().starts_with(self.name.as_bytes()));
+            if !ours {
+                continue;
+            }
+
+            // Blocks the runtime thread, for at most the busy timeout while a writer
+            // commits. The store is an Rc, so it can't move to spawn_blocking
+            let snapshot = match self.store.scan(&self.prefix) {
+                Ok(entries) => entries.into_iter().collect::<Snapshot>(),
+                // The writer still holds the lock, its commit will produce another event
+                Err(KvError::Busy) => continue,
+                Err(e) => return Some(Err(e)),
+            };
+
+            for (key, entry) in &snapshot {
+                if self.snapshot.get(key) != Some(entry) {
+                    self.pending.push_back(Change::Put(key.clone()));
+                }
+            }
+
+            for key in self.snapshot.keys() {
+                if !snapshot.contains_key(key) {
+                    self.pending.push_back(Change::Del(key.clone()));
+                }
+            }
+
+            self.snapshot = snapshot;
+        }
+    }
+}
 
 pub struct Kv {
-    connection: Connection,
+    store: Rc<dyn KvStore>,
+    // When `record_sample` last compacted, so it happens at most once a minute
+    compacted: Cell<i64>,
 }
 
 impl Kv {
-    pub fn new() -> Kv {
-        let dir = xdg::BaseDirectories::with_prefix("waybar")
-            .expect("No waybar directory found in XDG_CONFIG_DIRS");
-        let path = dir.get_config_file("rice.db");
-        let exists = path.exists();
-
-        let connection = Connection::open(path).expect("failed to open the database");
-        if !exists {
-            connection
-                .execute(
-                    "CREATE TABLE dictionary (
-                    key TEXT PRIMARY KEY,
-                    bool BOOLEAN,
-                    text TEXT,
-                    i64 INTEGER,
-                    f64 DECIMAL
-                )",
-                    [],
-                )
-                .expect("failed to create dictionary table");
-        }
-
-        Kv { connection }
-    }
-
-    pub fn put_bool(&self, key: &str, value: bool) -> () {
-        self.connection
-            .execute(
-                "INSERT INTO dictionary (key, bool) VALUES (?1, ?2) ON CONFLICT DO UPDATE SET bool = ?2",
-                rusqlite::params![key, value],
-            )
-            .expect("failed to insert into the database");
+    pub fn new() -> Result<Kv, KvError> {
+        Kv::open(Backend::Sqlite)
     }
 
-    pub fn put_string(&self, key: &str, value: &str) -> () {
-        self.connection
-            .execute(
-                "INSERT INTO dictionary (key, text) VALUES (?1, ?2) ON CONFLICT DO UPDATE SET text = ?2",
-                rusqlite::params![key, value],
-            )
-            .expect("failed to insert into the database");
+    pub fn open(backend: Backend) -> Result<Kv, KvError> {
+        let store: Rc<dyn KvStore> = match backend {
+            Backend::Sqlite => {
+                let dir =
+                    xdg::BaseDirectories::with_prefix("waybar").map_err(KvError::NoDirectory)?;
+                Rc::new(SqliteStore::open(&dir.get_config_file("rice.db"))?)
+            }
+            Backend::File => {
+                let dir =
+                    xdg::BaseDirectories::with_prefix("waybar").map_err(KvError::NoDirectory)?;
+                let path = dir.place_runtime_file("rice.json").map_err(KvError::Io)?;
+                Rc::new(FileStore::open(&path)?)
+            }
+            Backend::Memory => Rc::new(MemoryStore::new()),
+        };
+
+        Kv::with_store(store)
+    }
+
+    pub fn with_store(store: Rc<dyn KvStore>) -> Result<Kv, KvError> {
+        store.sweep()?;
+        Ok(Kv {
+            store,
+            compacted: Cell::new(0),
+        })
+    }
+
+    /// Deletes every expired key. Getters already skip them, this only reclaims the space.
+    pub fn sweep(&self) -> Result<(), KvError> {
+        self.store.sweep()
     }
 
-    pub fn put_i64(&self, key: &str, value: i64) -> () {
-        self.connection
-            .execute(
-                "INSERT INTO dictionary (key, i64) VALUES (?1, ?2) ON CONFLICT DO UPDATE SET i64 = ?2",
-                rusqlite::params![key, value],
+    /// Streams changes to keys starting with `prefix`, as written by any process.
+    /// Driven by inotify on the store's whole directory: a write to any file there wakes
+    /// the task, and a write to the store itself rescans `prefix`. There is no polling.
+    pub fn watch(
+        &self,
+        prefix: &str,
+    ) -> Result<impl Stream<Item = Result<Change, KvError>> + use<>, KvError> {
+        let Some(path) = self.store.path() else {
+            return Err(KvError::Watch(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the store is private to this process",
+            )));
+        };
+
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let name = path.file_name().unwrap_or_default().to_os_string();
+
+        let inotify = Inotify::init().map_err(KvError::Watch)?;
+        inotify
+            .watches()
+            .add(
+                dir,
+                WatchMask::MODIFY
+                    | WatchMask::CLOSE_WRITE
+                    | WatchMask::MOVED_TO
+                    | WatchMask::DELETE,
             )
-            .expect("failed to insert into the database");
+            .map_err(KvError::Watch)?;
+        let events = inotify
+            .into_event_stream([0; 1024])
+            .map_err(KvError::Watch)?;
+
+        let watcher = Watcher {
+            store: self.store.clone(),
+            events,
+            name,
+            prefix: prefix.to_string(),
+            snapshot: self.store.scan(prefix)?.into_iter().collect(),
+            pending: VecDeque::new(),
+        };
+
+        Ok(futures::stream::unfold(watcher, |mut watcher| async move {
+            watcher.next().await.map(|change| (change, watcher))
+        }))
     }
 
-    pub fn get_bool(&self, key: &str) -> Result<bool, rusqlite::Error> {
-        let mut stmt = self
-            .connection
-            .prepare("SELECT bool FROM dictionary WHERE key = ?1 LIMIT 1")
-            .expect("failed to prepare the statement");
+    fn get_column<T: Column>(&self, key: &str) -> Result<T, KvError> {
+        T::from_entry(self.store.entry(key)?)
+            .ok_or_else(|| KvError::TypeMismatch(format!("{} has no {} value", key, T::COLUMN)))
+    }
 
-        stmt.query_row([key], |row| row.get(0)) as Result<bool, rusqlite::Error>
+    /// Runs `f` atomically: everything it writes is committed together, or rolled back
+    /// when it returns an error. Calls nested inside `f` join the outer transaction.
+    pub fn transaction<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        E: From<KvError>,
+        F: FnOnce(&Kv) -> Result<T, E>,
+    {
+        if !self.store.begin()? {
+            return f(self);
+        }
+
+        match f(self) {
+            Ok(value) => match self.store.commit() {
+                Ok(()) => Ok(value),
+                Err(e) => {
+                    // A commit that failed, e.g. on a busy database, leaves the transaction open
+                    let _ = self.store.rollback();
+                    Err(e.into())
+                }
+            },
+            Err(e) => {
+                let _ = self.store.rollback();
+                Err(e)
+            }
+        }
     }
 
-    pub fn get_string(&self, key: &str) -> Result<String, rusqlite::Error> {
-        let mut stmt = self
-            .connection
-            .prepare("SELECT text FROM dictionary WHERE key = ?1 LIMIT 1")
-            .expect("failed to prepare the statement");
+    /// Writes `new` only if the key currently holds `expected`, where `None` means missing.
+    /// Returns whether the value was swapped.
+    pub fn compare_and_set<T: Column>(
+        &self,
+        key: &str,
+        expected: Option<T>,
+        new: T,
+    ) -> Result<bool, KvError> {
+        self.transaction(|tx| {
+            let current = match tx.store.entry(key) {
+                Ok(entry) => T::from_entry(entry),
+                Err(KvError::NotFound) => None,
+                Err(e) => return Err(e),
+            };
 
-        stmt.query_row([key], |row| row.get(0)) as Result<String, rusqlite::Error>
+            if current != expected {
+                return Ok(false);
+            }
+
+            tx.store.put(key, new.into_value(), None)?;
+            Ok(true)
+        })
     }
 
-    pub fn get_i64(&self, key: &str) -> Result<i64, rusqlite::Error> {
-        let mut stmt = self
-            .connection
-            .prepare("SELECT i64 FROM dictionary WHERE key = ?1 LIMIT 1")
-            .expect("failed to prepare the statement");
+    pub fn put_bool(&self, key: &str, value: bool) -> Result<(), KvError> {
+        self.store.put(key, Value::Bool(value), None)
+    }
 
-        stmt.query_row([key], |row| row.get(0)) as Result<i64, rusqlite::Error>
+    pub fn put_string(&self, key: &str, value: &str) -> Result<(), KvError> {
+        self.store.put(key, Value::Text(value.to_string()), None)
     }
 
-    pub fn del(&self, key: &str) -> () {
-        self.connection
-            .execute(
-                "DELETE FROM dictionary WHERE key = ?1",
-                rusqlite::params![key],
-            )
-            .expect("failed to delete from the database");
+    pub fn put_i64(&self, key: &str, value: i64) -> Result<(), KvError> {
+        self.store.put(key, Value::I64(value), None)
+    }
+
+    pub fn put_f64(&self, key: &str, value: f64) -> Result<(), KvError> {
+        self.store.put(key, Value::F64(value), None)
+    }
+
+    /// Stores any serializable value as JSON, e.g. a whole bulb scene in one call.
+    pub fn put<T: Serialize + ?Sized>(&self, key: &str, value: &T) -> Result<(), KvError> {
+        let json = serde_json::to_value(value).map_err(KvError::Json)?;
+        self.store.put(key, Value::Json(json), None)
+    }
+
+    /// Like `put_bool`, but the key reads as missing once `ttl` has passed.
+    pub fn put_bool_with_ttl(&self, key: &str, value: bool, ttl: Duration) -> Result<(), KvError> {
+        self.store.put(key, Value::Bool(value), expires_at(ttl))
+    }
+
+    pub fn put_string_with_ttl(
+        &self,
+        key: &str,
+        value: &str,
+        ttl: Duration,
+    ) -> Result<(), KvError> {
+        self.store
+            .put(key, Value::Text(value.to_string()), expires_at(ttl))
+    }
+
+    pub fn put_i64_with_ttl(&self, key: &str, value: i64, ttl: Duration) -> Result<(), KvError> {
+        self.store.put(key, Value::I64(value), expires_at(ttl))
+    }
+
+    pub fn put_f64_with_ttl(&self, key: &str, value: f64, ttl: Duration) -> Result<(), KvError> {
+        self.store.put(key, Value::F64(value), expires_at(ttl))
+    }
+
+    pub fn put_with_ttl<T: Serialize + ?Sized>(
+        &self,
+        key: &str,
+        value: &T,
+        ttl: Duration,
+    ) -> Result<(), KvError> {
+        let json = serde_json::to_value(value).map_err(KvError::Json)?;
+        self.store.put(key, Value::Json(json), expires_at(ttl))
+    }
+
+    pub fn get_bool(&self, key: &str) -> Result<bool, KvError> {
+        self.get_column(key)
+    }
+
+    pub fn get_string(&self, key: &str) -> Result<String, KvError> {
+        self.get_column(key)
+    }
+
+    pub fn get_i64(&self, key: &str) -> Result<i64, KvError> {
+        self.get_column(key)
+    }
+
+    pub fn get_f64(&self, key: &str) -> Result<f64, KvError> {
+        self.get_column(key)
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<T, KvError> {
+        let json = self
+            .store
+            .entry(key)?
+            .json
+            .ok_or_else(|| KvError::TypeMismatch(format!("{} has no json value", key)))?;
+        serde_json::from_value(json).map_err(|e| KvError::TypeMismatch(e.to_string()))
+    }
+
+    /// Unix seconds at which `key` reads as missing, `None` if it never expires.
+    pub fn expires_at(&self, key: &str) -> Result<Option<i64>, KvError> {
+        self.store.expires_at(key)
+    }
+
+    /// Writes every field of `entry` at once, expiring at `expires_at` if given,
+    /// e.g. to restore what `scan` and `expires_at` returned.
+    pub fn put_entry(
+        &self,
+        key: &str,
+        entry: Entry,
+        expires_at: Option<i64>,
+    ) -> Result<(), KvError> {
+        self.transaction(|tx| {
+            for value in entry.values() {
+                tx.store.put(key, value, expires_at)?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn del(&self, key: &str) -> Result<(), KvError> {
+        self.store.del(key)
+    }
+
+    pub fn keys(&self, prefix: &str) -> Result<Vec<String>, KvError> {
+        let entries = self.store.scan(prefix)?;
+        Ok(entries.into_iter().map(|(key, _)| key).collect())
+    }
+
+    pub fn scan(&self, prefix: &str) -> Result<Vec<(String, Entry)>, KvError> {
+        self.store.scan(prefix)
+    }
+
+    /// Deletes every key starting with `prefix` and returns how many were removed.
+    pub fn del_prefix(&self, prefix: &str) -> Result<usize, KvError> {
+        self.store.del_prefix(prefix)
+    }
+
+    /// Appends `value` to `series`, stamped with the current time.
+    pub fn record_sample(&self, series: &str, value: f64) -> Result<(), KvError> {
+        let now = now();
+        self.store
+            .record_sample(series, Sample { at: now, value })?;
+
+        if now - self.compacted.get() >= 60 {
+            self.compact_samples()?;
+        }
+
+        Ok(())
+    }
+
+    /// Samples of `series` taken at or after `since` (unix seconds), oldest first.
+    pub fn samples(&self, series: &str, since: i64) -> Result<Vec<Sample>, KvError> {
+        self.store.samples(series, since)
+    }
+
+    /// Downsamples and expires old samples of every series. `record_sample` already
+    /// does this once a minute.
+    pub fn compact_samples(&self) -> Result<(), KvError> {
+        let now = now();
+
+        for &(age, bucket) in SAMPLE_TIERS {
+            let before = (now - age).div_euclid(bucket) * bucket;
+            self.store.compact_samples(before, bucket)?;
+        }
+        self.store.prune_samples(now - SAMPLE_RETENTION)?;
+
+        self.compacted.set(now);
+        Ok(())
+    }
+
+    /// Scopes every key under `name:`, so modules can't stomp on each other's state.
+    pub fn namespace(&self, name: &str) -> Namespace<'_> {
+        Namespace {
+            kv: self,
+            prefix: format!("{}:", name),
+        }
+    }
+}
+
+pub struct Namespace<'a> {
+    kv: &'a Kv,
+    prefix: String,
+}
+
+impl Namespace<'_> {
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    fn strip(&self, key: String) -> String {
+        key[self.prefix.len()..].to_string()
+    }
+
+    pub fn namespace(&self, name: &str) -> Namespace<'_> {
+        Namespace {
+            kv: self.kv,
+            prefix: format!("{}{}:", self.prefix, name),
+        }
+    }
+
+    pub fn put_bool(&self, key: &str, value: bool) -> Result<(), KvError> {
+        self.kv.put_bool(&self.key(key), value)
+    }
+
+    pub fn put_string(&self, key: &str, value: &str) -> Result<(), KvError> {
+        self.kv.put_string(&self.key(key), value)
+    }
+
+    pub fn put_i64(&self, key: &str, value: i64) -> Result<(), KvError> {
+        self.kv.put_i64(&self.key(key), value)
+    }
+
+    pub fn put_f64(&self, key: &str, value: f64) -> Result<(), KvError> {
+        self.kv.put_f64(&self.key(key), value)
+    }
+
+    pub fn put<T: Serialize + ?Sized>(&self, key: &str, value: &T) -> Result<(), KvError> {
+        self.kv.put(&self.key(key), value)
+    }
+
+    pub fn put_bool_with_ttl(&self, key: &str, value: bool, ttl: Duration) -> Result<(), KvError> {
+        self.kv.put_bool_with_ttl(&self.key(key), value, ttl)
+    }
+
+    pub fn put_string_with_ttl(
+        &self,
+        key: &str,
+        value: &str,
+        ttl: Duration,
+    ) -> Result<(), KvError> {
+        self.kv.put_string_with_ttl(&self.key(key), value, ttl)
+    }
+
+    pub fn put_i64_with_ttl(&self, key: &str, value: i64, ttl: Duration) -> Result<(), KvError> {
+        self.kv.put_i64_with_ttl(&self.key(key), value, ttl)
+    }
+
+    pub fn put_f64_with_ttl(&self, key: &str, value: f64, ttl: Duration) -> Result<(), KvError> {
+        self.kv.put_f64_with_ttl(&self.key(key), value, ttl)
+    }
+
+    pub fn put_with_ttl<T: Serialize + ?Sized>(
+        &self,
+        key: &str,
+        value: &T,
+        ttl: Duration,
+    ) -> Result<(), KvError> {
+        self.kv.put_with_ttl(&self.key(key), value, ttl)
+    }
+
+    pub fn get_bool(&self, key: &str) -> Result<bool, KvError> {
+        self.kv.get_bool(&self.key(key))
+    }
+
+    pub fn get_string(&self, key: &str) -> Result<String, KvError> {
+        self.kv.get_string(&self.key(key))
+    }
+
+    pub fn get_i64(&self, key: &str) -> Result<i64, KvError> {
+        self.kv.get_i64(&self.key(key))
+    }
+
+    pub fn get_f64(&self, key: &str) -> Result<f64, KvError> {
+        self.kv.get_f64(&self.key(key))
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<T, KvError> {
+        self.kv.get(&self.key(key))
+    }
+
+    pub fn compare_and_set<T: Column>(
+        &self,
+        key: &str,
+        expected: Option<T>,
+        new: T,
+    ) -> Result<bool, KvError> {
+        self.kv.compare_and_set(&self.key(key), expected, new)
+    }
+
+    pub fn expires_at(&self, key: &str) -> Result<Option<i64>, KvError> {
+        self.kv.expires_at(&self.key(key))
+    }
+
+    pub fn put_entry(
+        &self,
+        key: &str,
+        entry: Entry,
+        expires_at: Option<i64>,
+    ) -> Result<(), KvError> {
+        self.kv.put_entry(&self.key(key), entry, expires_at)
+    }
+
+    pub fn del(&self, key: &str) -> Result<(), KvError> {
+        self.kv.del(&self.key(key))
+    }
+
+    /// Keys relative to the namespace, e.g. `start` rather than `recording:start`.
+    pub fn keys(&self, prefix: &str) -> Result<Vec<String>, KvError> {
+        let keys = self.kv.keys(&self.key(prefix))?;
+        Ok(keys.into_iter().map(|key| self.strip(key)).collect())
+    }
+
+    pub fn scan(&self, prefix: &str) -> Result<Vec<(String, Entry)>, KvError> {
+        let entries = self.kv.scan(&self.key(prefix))?;
+        Ok(entries
+            .into_iter()
+            .map(|(key, entry)| (self.strip(key), entry))
+            .collect())
+    }
+
+    pub fn del_prefix(&self, prefix: &str) -> Result<usize, KvError> {
+        self.kv.del_prefix(&self.key(prefix))
+    }
+
+    /// Deletes every key in the namespace.
+    pub fn clear(&self) -> Result<usize, KvError> {
+        self.kv.del_prefix(&self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::{env, fs, process};
+
+    /// A directory of its own for each test's store files, removed afterwards.
+    pub(super) struct TempDir(pub PathBuf);
+
+    impl TempDir {
+        pub fn new(name: &str) -> TempDir {
+            let path = env::temp_dir().join(format!("riceutils-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// The same tests run against every store.
+    fn stores(dir: &TempDir) -> Vec<Kv> {
+        let stores: [Rc<dyn KvStore>; 3] = [
+            Rc::new(MemoryStore::new()),
+            Rc::new(FileStore::open(&dir.0.join("rice.json")).unwrap()),
+            Rc::new(SqliteStore::open(&dir.0.join("rice.db")).unwrap()),
+        ];
+        stores
+            .into_iter()
+            .map(|store| Kv::with_store(store).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn errors() {
+        let dir = TempDir::new("errors");
+        for kv in stores(&dir) {
+            assert!(matches!(kv.get_bool("missing"), Err(KvError::NotFound)));
+
+            kv.put_bool("flag", true).unwrap();
+            assert!(matches!(kv.get_i64("flag"), Err(KvError::TypeMismatch(_))));
+            assert!(matches!(
+                kv.get::<Vec<u32>>("flag"),
+                Err(KvError::TypeMismatch(_))
+            ));
+        }
+
+        fs::write(dir.0.join("broken.json"), "{").unwrap();
+        let kv = Kv::with_store(Rc::new(
+            FileStore::open(&dir.0.join("broken.json")).unwrap(),
+        ));
+        assert!(matches!(kv, Err(KvError::SchemaMismatch(_))));
+    }
+
+    #[test]
+    fn typed_columns() {
+        let dir = TempDir::new("typed-columns");
+        for kv in stores(&dir) {
+            kv.put_i64("volume", 40).unwrap();
+            kv.put_string("volume", "forty").unwrap();
+            assert_eq!(kv.get_i64("volume").unwrap(), 40);
+            assert_eq!(kv.get_string("volume").unwrap(), "forty");
+
+            kv.del("volume").unwrap();
+            assert!(matches!(kv.get_i64("volume"), Err(KvError::NotFound)));
+        }
+    }
+
+    #[test]
+    fn ttl() {
+        let dir = TempDir::new("ttl");
+        for kv in stores(&dir) {
+            // Expires right away
+            kv.put_i64_with_ttl("lease", 1, Duration::ZERO).unwrap();
+            kv.put_string_with_ttl("lease", "holder", Duration::ZERO)
+                .unwrap();
+            kv.put_bool_with_ttl("fresh", true, Duration::from_secs(60))
+                .unwrap();
+            assert!(matches!(kv.get_i64("lease"), Err(KvError::NotFound)));
+            assert_eq!(kv.keys("").unwrap(), ["fresh"]);
+            assert!(kv.get_bool("fresh").unwrap());
+
+            // An expired key doesn't lend its old columns to a new value
+            kv.put_bool("lease", true).unwrap();
+            assert!(kv.get_bool("lease").unwrap());
+            assert!(matches!(kv.get_i64("lease"), Err(KvError::TypeMismatch(_))));
+            assert!(matches!(
+                kv.get_string("lease"),
+                Err(KvError::TypeMismatch(_))
+            ));
+
+            kv.put_f64_with_ttl("gone", 1.5, Duration::ZERO).unwrap();
+            kv.sweep().unwrap();
+            assert_eq!(kv.del_prefix("gone").unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn entries() {
+        let dir = TempDir::new("entries");
+        for kv in stores(&dir) {
+            kv.put_i64("count", 3).unwrap();
+            kv.put_string_with_ttl("lease", "holder", Duration::from_secs(60))
+                .unwrap();
+            assert_eq!(kv.expires_at("count").unwrap(), None);
+            let expires_at = kv.expires_at("lease").unwrap().unwrap();
+            assert!(expires_at > now());
+            assert!(matches!(kv.expires_at("missing"), Err(KvError::NotFound)));
+
+            // What export writes, imported under other names
+            for (key, entry) in kv.scan("").unwrap() {
+                let expires_at = kv.expires_at(&key).unwrap();
+                kv.namespace("copy")
+                    .put_entry(&key, entry, expires_at)
+                    .unwrap();
+            }
+            let copy = kv.namespace("copy");
+            assert_eq!(copy.get_i64("count").unwrap(), 3);
+            assert_eq!(copy.get_string("lease").unwrap(), "holder");
+            assert_eq!(copy.expires_at("lease").unwrap(), Some(expires_at));
+
+            let entry = kv.scan("count").unwrap().remove(0).1;
+            kv.put_entry("expired", entry, Some(now())).unwrap();
+            assert!(matches!(kv.get_i64("expired"), Err(KvError::NotFound)));
+        }
+    }
+
+    #[test]
+    fn json() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Scene {
+            name: String,
+            brightness: u8,
+        }
+
+        let dir = TempDir::new("json");
+        for kv in stores(&dir) {
+            let scene = Scene {
+                name: "evening".to_string(),
+                brightness: 30,
+            };
+            kv.put("scene", &scene).unwrap();
+            assert_eq!(kv.get::<Scene>("scene").unwrap(), scene);
+            assert!(matches!(
+                kv.get::<Vec<u8>>("scene"),
+                Err(KvError::TypeMismatch(_))
+            ));
+
+            kv.put_with_ttl("scene", &[1, 2, 3], Duration::ZERO)
+                .unwrap();
+            assert!(matches!(kv.get::<Vec<u8>>("scene"), Err(KvError::NotFound)));
+        }
+    }
+
+    #[test]
+    fn namespaces() {
+        let dir = TempDir::new("namespaces");
+        for kv in stores(&dir) {
+            let recording = kv.namespace("recording");
+            recording.put_i64("start", 10).unwrap();
+            recording.put_i64("pid", 42).unwrap();
+            recording.namespace("last").put_i64("start", 5).unwrap();
+            kv.put_bool("recording", true).unwrap();
+
+            assert_eq!(kv.get_i64("recording:start").unwrap(), 10);
+            assert_eq!(recording.keys("").unwrap(), ["last:start", "pid", "start"]);
+            assert_eq!(recording.namespace("last").keys("").unwrap(), ["start"]);
+            assert_eq!(recording.scan("p").unwrap()[0].0, "pid");
+
+            assert_eq!(recording.del_prefix("last").unwrap(), 1);
+            assert_eq!(recording.clear().unwrap(), 2);
+            assert_eq!(kv.keys("").unwrap(), ["recording"]);
+        }
+    }
+
+    #[test]
+    fn transactions() {
+        let dir = TempDir::new("transactions");
+        for kv in stores(&dir) {
+            assert!(kv.compare_and_set("count", None, 1i64).unwrap());
+            assert!(!kv.compare_and_set("count", None, 2i64).unwrap());
+            assert!(!kv.compare_and_set("count", Some(5), 2i64).unwrap());
+            assert!(kv.compare_and_set("count", Some(1), 2i64).unwrap());
+            assert_eq!(kv.get_i64("count").unwrap(), 2);
+
+            // The nested compare_and_set joins the outer transaction and is rolled back with it
+            let result = kv.transaction(|tx| {
+                tx.put_bool("outer", true)?;
+                assert!(tx.compare_and_set("count", Some(2), 3i64)?);
+                Err::<(), _>(KvError::NotFound)
+            });
+            assert!(matches!(result, Err(KvError::NotFound)));
+            assert!(matches!(kv.get_bool("outer"), Err(KvError::NotFound)));
+            assert_eq!(kv.get_i64("count").unwrap(), 2);
+
+            kv.transaction(|tx| {
+                tx.put_bool("outer", true)?;
+                tx.compare_and_set("count", Some(2), 3i64)
+            })
+            .unwrap();
+            assert!(kv.get_bool("outer").unwrap());
+            assert_eq!(kv.get_i64("count").unwrap(), 3);
+        }
+    }
+
+    #[test]
+    fn compaction() {
+        let dir = TempDir::new("compaction");
+        let now = now();
+        let minute = (now - 2 * 60 * 60).div_euclid(60) * 60;
+        let quarter = (now - 2 * 24 * 60 * 60).div_euclid(15 * 60) * 15 * 60;
+
+        for kv in stores(&dir) {
+            for (at, value) in [
+                (now - 8 * 24 * 60 * 60, 9.0),
+                (quarter + 10, 4.0),
+                (quarter + 700, 6.0),
+                (minute + 1, 1.0),
+                (minute + 2, 3.0),
+                (now - 10, 7.0),
+            ] {
+                kv.store
+                    .record_sample("power", Sample { at, value })
+                    .unwrap();
+            }
+            kv.compact_samples().unwrap();
+
+            assert_eq!(
+                kv.samples("power", 0).unwrap(),
+                [
+                    Sample {
+                        at: quarter,
+                        value: 5.0
+                    },
+                    Sample {
+                        at: minute,
+                        value: 2.0
+                    },
+                    Sample {
+                        at: now - 10,
+                        value: 7.0
+                    },
+                ]
+            );
+            assert_eq!(kv.samples("power", now - 60).unwrap().len(), 1);
+            assert!(kv.samples("other", 0).unwrap().is_empty());
+        }
     }
 }
@@ -0,0 +1,239 @@
+use super::memory::{MemoryStore, Record};
+use super::{Entry, KvError, KvStore, Sample, Value};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A JSON file for volatile state, meant for `$XDG_RUNTIME_DIR` so it's gone after a reboot.
+/// Every operation locks the file, loads it, and writes it back if anything changed.
+/// Samples go to a hidden sibling file with its own lock, outside of transactions.
+pub struct FileStore {
+    path: PathBuf,
+    memory: MemoryStore,
+    // Held for the whole of a transaction
+    lock: RefCell<Option<File>>,
+    // Whether the transaction wrote anything for `commit` to save
+    dirty: Cell<bool>,
+}
+
+impl FileStore {
+    pub fn open(path: &Path) -> Result<FileStore, KvError> {
+        Ok(FileStore {
+            path: path.to_path_buf(),
+            memory: MemoryStore::new(),
+            lock: RefCell::new(None),
+            dirty: Cell::new(false),
+        })
+    }
+
+    // Hidden siblings so `Kv::watch`, which matches on the file name, doesn't see them
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        self.path.with_file_name(format!(".{}.{}", name, suffix))
+    }
+
+    fn lock(&self, name: &str) -> Result<File, KvError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.sibling(name))
+            .map_err(KvError::Io)?;
+        file.lock().map_err(KvError::Io)?;
+        Ok(file)
+    }
+
+    fn read<T: DeserializeOwned + Default>(path: &Path) -> Result<T, KvError> {
+        match fs::read(path) {
+            Ok(data) => {
+                serde_json::from_slice(&data).map_err(|e| KvError::SchemaMismatch(e.to_string()))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+            Err(e) => Err(KvError::Io(e)),
+        }
+    }
+
+    fn write<T: Serialize>(path: &Path, value: &T) -> Result<(), KvError> {
+        let data = serde_json::to_vec(value).map_err(KvError::Json)?;
+
+        // Rename over the old file so readers never see a half-written one
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let tmp = path.with_file_name(format!(".{}.tmp", name.trim_start_matches('.')));
+        fs::write(&tmp, data).map_err(KvError::Io)?;
+        fs::rename(&tmp, path).map_err(KvError::Io)
+    }
+
+    fn load(&self) -> Result<(), KvError> {
+        self.memory.replace(FileStore::read(&self.path)?);
+        Ok(())
+    }
+
+    fn save(&self) -> Result<(), KvError> {
+        let records: BTreeMap<String, Record> = self.memory.records();
+        FileStore::write(&self.path, &records)
+    }
+
+    fn with_samples<T>(
+        &self,
+        write: bool,
+        f: impl FnOnce(&MemoryStore) -> Result<T, KvError>,
+    ) -> Result<T, KvError> {
+        let path = self.sibling("samples");
+
+        let _lock = self.lock("samples.lock")?;
+        self.memory.replace_samples(FileStore::read(&path)?);
+
+        let value = f(&self.memory)?;
+        if write {
+            FileStore::write(&path, &self.memory.all_samples())?;
+        }
+
+        Ok(value)
+    }
+
+    /// Runs `f` on the file's records, saving them when `changed` says that its result
+    /// changed anything, so that reads don't wake `Kv::watch` listeners.
+    fn with<T>(
+        &self,
+        changed: impl FnOnce(&T) -> bool,
+        f: impl FnOnce(&MemoryStore) -> Result<T, KvError>,
+    ) -> Result<T, KvError> {
+        if self.lock.borrow().is_some() {
+            let value = f(&self.memory)?;
+            if changed(&value) {
+                self.dirty.set(true);
+            }
+            return Ok(value);
+        }
+
+        let _lock = self.lock("lock")?;
+        self.load()?;
+
+        let value = f(&self.memory)?;
+        if changed(&value) {
+            self.save()?;
+        }
+
+        Ok(value)
+    }
+}
+
+impl KvStore for FileStore {
+    fn put(&self, key: &str, value: Value, expires_at: Option<i64>) -> Result<(), KvError> {
+        self.with(|_| true, |memory| memory.put(key, value, expires_at))
+    }
+
+    fn entry(&self, key: &str) -> Result<Entry, KvError> {
+        self.with(|_| false, |memory| memory.entry(key))
+    }
+
+    fn expires_at(&self, key: &str) -> Result<Option<i64>, KvError> {
+        self.with(|_| false, |memory| memory.expires_at(key))
+    }
+
+    fn del(&self, key: &str) -> Result<(), KvError> {
+        self.with(|removed| *removed, |memory| Ok(memory.remove(key)))?;
+        Ok(())
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Entry)>, KvError> {
+        self.with(|_| false, |memory| memory.scan(prefix))
+    }
+
+    fn del_prefix(&self, prefix: &str) -> Result<usize, KvError> {
+        self.with(|removed| *removed > 0, |memory| memory.del_prefix(prefix))
+    }
+
+    fn sweep(&self) -> Result<(), KvError> {
+        self.with(|expired| *expired > 0, |memory| Ok(memory.expire()))?;
+        Ok(())
+    }
+
+    fn begin(&self) -> Result<bool, KvError> {
+        if self.lock.borrow().is_some() {
+            return Ok(false);
+        }
+
+        let lock = self.lock("lock")?;
+        self.load()?;
+        *self.lock.borrow_mut() = Some(lock);
+        self.dirty.set(false);
+        Ok(true)
+    }
+
+    fn commit(&self) -> Result<(), KvError> {
+        let result = if self.dirty.take() {
+            self.save()
+        } else {
+            Ok(())
+        };
+        self.lock.borrow_mut().take();
+        result
+    }
+
+    fn rollback(&self) -> Result<(), KvError> {
+        // Nothing reached the file yet, the next operation reloads it
+        self.dirty.set(false);
+        self.lock.borrow_mut().take();
+        Ok(())
+    }
+
+    fn path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+
+    fn record_sample(&self, series: &str, sample: Sample) -> Result<(), KvError> {
+        self.with_samples(true, |memory| memory.record_sample(series, sample))
+    }
+
+    fn samples(&self, series: &str, since: i64) -> Result<Vec<Sample>, KvError> {
+        self.with_samples(false, |memory| memory.samples(series, since))
+    }
+
+    fn compact_samples(&self, before: i64, bucket: i64) -> Result<(), KvError> {
+        self.with_samples(true, |memory| memory.compact_samples(before, bucket))
+    }
+
+    fn prune_samples(&self, before: i64) -> Result<(), KvError> {
+        self.with_samples(true, |memory| memory.prune_samples(before))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::Kv;
+    use crate::kv::tests::TempDir;
+    use std::os::unix::fs::MetadataExt;
+    use std::rc::Rc;
+
+    // Every save renames a new file over the old one
+    fn inode(path: &Path) -> u64 {
+        fs::metadata(path).unwrap().ino()
+    }
+
+    #[test]
+    fn saves_only_changes() {
+        let dir = TempDir::new("file-store");
+        let path = dir.0.join("rice.json");
+        let kv = Kv::with_store(Rc::new(FileStore::open(&path).unwrap())).unwrap();
+        kv.put_bool("recording", false).unwrap();
+        let written = inode(&path);
+
+        // Reads, deletes that miss and transactions that only read leave the file alone
+        Kv::with_store(Rc::new(FileStore::open(&path).unwrap())).unwrap();
+        assert!(!kv.get_bool("recording").unwrap());
+        kv.del("missing").unwrap();
+        assert_eq!(kv.del_prefix("missing").unwrap(), 0);
+        assert!(!kv.compare_and_set("recording", None, true).unwrap());
+        assert_eq!(inode(&path), written);
+
+        kv.del("recording").unwrap();
+        assert_ne!(inode(&path), written);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{}");
+    }
+}
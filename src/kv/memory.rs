@@ -0,0 +1,209 @@
+use super::{Entry, KvError, KvStore, Sample, Value, now};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(super) struct Record {
+    #[serde(flatten)]
+    pub entry: Entry,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+}
+
+impl Record {
+    fn alive(&self, now: i64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+/// A store private to this process, gone when it exits. Meant for tests.
+#[derive(Default)]
+pub struct MemoryStore {
+    records: RefCell<BTreeMap<String, Record>>,
+    // Copy of `records` taken by `begin`, restored by `rollback`
+    backup: RefCell<Option<BTreeMap<String, Record>>>,
+    samples: RefCell<BTreeMap<String, Vec<Sample>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    pub(super) fn records(&self) -> BTreeMap<String, Record> {
+        self.records.borrow().clone()
+    }
+
+    pub(super) fn replace(&self, records: BTreeMap<String, Record>) {
+        *self.records.borrow_mut() = records;
+    }
+
+    /// Deletes `key`, returning whether it was there, even if expired.
+    pub(super) fn remove(&self, key: &str) -> bool {
+        self.records.borrow_mut().remove(key).is_some()
+    }
+
+    /// Deletes expired records, returning how many.
+    pub(super) fn expire(&self) -> usize {
+        let now = now();
+        let mut records = self.records.borrow_mut();
+        let before = records.len();
+        records.retain(|_, record| record.alive(now));
+        before - records.len()
+    }
+
+    pub(super) fn all_samples(&self) -> BTreeMap<String, Vec<Sample>> {
+        self.samples.borrow().clone()
+    }
+
+    pub(super) fn replace_samples(&self, samples: BTreeMap<String, Vec<Sample>>) {
+        *self.samples.borrow_mut() = samples;
+    }
+}
+
+impl KvStore for MemoryStore {
+    fn put(&self, key: &str, value: Value, expires_at: Option<i64>) -> Result<(), KvError> {
+        let mut records = self.records.borrow_mut();
+        let record = records.entry(key.to_string()).or_default();
+
+        // An expired record must not lend its other fields to the new value
+        if !record.alive(now()) {
+            *record = Record::default();
+        }
+
+        record.entry.set(value);
+        record.expires_at = expires_at;
+        Ok(())
+    }
+
+    fn entry(&self, key: &str) -> Result<Entry, KvError> {
+        match self.records.borrow().get(key) {
+            Some(record) if record.alive(now()) => Ok(record.entry.clone()),
+            _ => Err(KvError::NotFound),
+        }
+    }
+
+    fn expires_at(&self, key: &str) -> Result<Option<i64>, KvError> {
+        match self.records.borrow().get(key) {
+            Some(record) if record.alive(now()) => Ok(record.expires_at),
+            _ => Err(KvError::NotFound),
+        }
+    }
+
+    fn del(&self, key: &str) -> Result<(), KvError> {
+        self.remove(key);
+        Ok(())
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Entry)>, KvError> {
+        let now = now();
+        Ok(self
+            .records
+            .borrow()
+            .iter()
+            .filter(|(key, record)| key.starts_with(prefix) && record.alive(now))
+            .map(|(key, record)| (key.clone(), record.entry.clone()))
+            .collect())
+    }
+
+    fn del_prefix(&self, prefix: &str) -> Result<usize, KvError> {
+        let mut records = self.records.borrow_mut();
+        let before = records.len();
+        records.retain(|key, _| !key.starts_with(prefix));
+        Ok(before - records.len())
+    }
+
+    fn sweep(&self) -> Result<(), KvError> {
+        self.expire();
+        Ok(())
+    }
+
+    fn begin(&self) -> Result<bool, KvError> {
+        let mut backup = self.backup.borrow_mut();
+        if backup.is_some() {
+            return Ok(false);
+        }
+
+        *backup = Some(self.records());
+        Ok(true)
+    }
+
+    fn commit(&self) -> Result<(), KvError> {
+        self.backup.borrow_mut().take();
+        Ok(())
+    }
+
+    fn rollback(&self) -> Result<(), KvError> {
+        if let Some(records) = self.backup.borrow_mut().take() {
+            self.replace(records);
+        }
+        Ok(())
+    }
+
+    fn path(&self) -> Option<&Path> {
+        None
+    }
+
+    fn record_sample(&self, series: &str, sample: Sample) -> Result<(), KvError> {
+        let mut samples = self.samples.borrow_mut();
+        let samples = samples.entry(series.to_string()).or_default();
+
+        // Kept sorted by time, the clock may have stepped back
+        let i = samples.partition_point(|s| s.at < sample.at);
+        match samples.get_mut(i) {
+            Some(s) if s.at == sample.at => *s = sample,
+            _ => samples.insert(i, sample),
+        }
+        Ok(())
+    }
+
+    fn samples(&self, series: &str, since: i64) -> Result<Vec<Sample>, KvError> {
+        Ok(self
+            .samples
+            .borrow()
+            .get(series)
+            .map(|samples| samples.iter().filter(|s| s.at >= since).copied().collect())
+            .unwrap_or_default())
+    }
+
+    fn compact_samples(&self, before: i64, bucket: i64) -> Result<(), KvError> {
+        for samples in self.samples.borrow_mut().values_mut() {
+            let split = samples.partition_point(|s| s.at < before);
+            let recent = samples.split_off(split);
+
+            let mut compacted: Vec<Sample> = Vec::new();
+            let mut count = 0;
+            for sample in samples.drain(..) {
+                let start = sample.at.div_euclid(bucket) * bucket;
+                match compacted.last_mut() {
+                    Some(last) if last.at == start => {
+                        count += 1;
+                        last.value += (sample.value - last.value) / count as f64;
+                    }
+                    _ => {
+                        count = 1;
+                        compacted.push(Sample {
+                            at: start,
+                            value: sample.value,
+                        });
+                    }
+                }
+            }
+
+            compacted.extend(recent);
+            *samples = compacted;
+        }
+        Ok(())
+    }
+
+    fn prune_samples(&self, before: i64) -> Result<(), KvError> {
+        let mut samples = self.samples.borrow_mut();
+        for series in samples.values_mut() {
+            series.retain(|s| s.at >= before);
+        }
+        samples.retain(|_, series| !series.is_empty());
+        Ok(())
+    }
+}
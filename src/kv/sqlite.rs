@@ -0,0 +1,499 @@
+use super::{Entry, KvError, KvStore, Sample, Value, now};
+use rusqlite::types::Type;
+use rusqlite::{Connection, ErrorCode, Row, TransactionBehavior};
+use std::cell::OnceCell;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+impl From<rusqlite::Error> for KvError {
+    fn from(e: rusqlite::Error) -> Self {
+        match e {
+            rusqlite::Error::QueryReturnedNoRows => KvError::NotFound,
+            rusqlite::Error::InvalidColumnType(_, name, ty) => {
+                KvError::TypeMismatch(format!("column {} holds {}", name, ty))
+            }
+            rusqlite::Error::FromSqlConversionFailure(_, _, e) => {
+                KvError::TypeMismatch(e.to_string())
+            }
+            rusqlite::Error::SqliteFailure(code, _)
+                if code.code == ErrorCode::DatabaseBusy
+                    || code.code == ErrorCode::DatabaseLocked =>
+            {
+                KvError::Busy
+            }
+            rusqlite::Error::SqliteFailure(_, Some(ref msg))
+                if msg.starts_with("no such table") || msg.starts_with("no such column") =>
+            {
+                KvError::SchemaMismatch(msg.clone())
+            }
+            e => KvError::Sqlite(e),
+        }
+    }
+}
+
+/// Schema migrations, applied in order. The database's `user_version` records
+/// how many of them have already run, so entries must never be edited or reordered.
+const MIGRATIONS: &[&str] = &[
+    // Databases created before versioning already have this table
+    "CREATE TABLE IF NOT EXISTS dictionary (
+        key TEXT PRIMARY KEY,
+        bool BOOLEAN,
+        text TEXT,
+        i64 INTEGER,
+        f64 DECIMAL
+    )",
+    "ALTER TABLE dictionary ADD COLUMN expires_at INTEGER",
+    "ALTER TABLE dictionary ADD COLUMN json TEXT",
+];
+
+/// Migrations for the samples database, kept apart from `rice.db` so that
+/// frequent samples don't wake every `Kv::watch` listener.
+const SAMPLE_MIGRATIONS: &[&str] = &["
+    CREATE TABLE series (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE
+    );
+    CREATE TABLE samples (
+        series INTEGER NOT NULL,
+        at INTEGER NOT NULL,
+        value REAL NOT NULL,
+        PRIMARY KEY (series, at)
+    ) WITHOUT ROWID;
+"];
+
+/// How long to wait for another process' write lock before failing with `KvError::Busy`.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The number of migrations already applied.
+fn version(connection: &Connection, migrations: &[&str]) -> Result<usize, KvError> {
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > migrations.len() {
+        return Err(KvError::SchemaMismatch(format!(
+            "database version {} is newer than the supported version {}",
+            version,
+            migrations.len()
+        )));
+    }
+    Ok(version)
+}
+
+fn migrate(connection: &mut Connection, migrations: &[&str]) -> Result<(), KvError> {
+    // Up to date databases are only read, so opening one doesn't wake watchers
+    if version(connection, migrations)? == migrations.len() {
+        return Ok(());
+    }
+
+    // Take the write lock so two processes can't both apply the same migration,
+    // then look again in case another one just did
+    let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let version = version(&tx, migrations)?;
+    if version == migrations.len() {
+        return Ok(());
+    }
+
+    for migration in &migrations[version..] {
+        tx.execute_batch(migration)?;
+    }
+
+    tx.pragma_update(None, "user_version", migrations.len())?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Reads the `bool, text, i64, f64, json` columns starting at `offset`.
+fn entry(row: &Row, offset: usize) -> rusqlite::Result<Entry> {
+    let json = row
+        .get::<_, Option<String>>(offset + 4)?
+        .map(|json| serde_json::from_str(&json))
+        .transpose()
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(offset + 4, Type::Text, e.into()))?;
+
+    Ok(Entry {
+        bool: row.get(offset)?,
+        text: row.get(offset + 1)?,
+        i64: row.get(offset + 2)?,
+        f64: row.get(offset + 3)?,
+        json,
+    })
+}
+
+/// The persistent store, `rice.db` in the waybar config directory,
+/// with samples in `rice.samples.db` next to it.
+pub struct SqliteStore {
+    connection: Connection,
+    // Opened on first use, so stores that never sample don't create the file
+    samples: OnceCell<Connection>,
+    path: PathBuf,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<SqliteStore, KvError> {
+        let mut connection = Connection::open(path).map_err(KvError::Open)?;
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        migrate(&mut connection, MIGRATIONS)?;
+
+        Ok(SqliteStore {
+            connection,
+            samples: OnceCell::new(),
+            path: path.to_path_buf(),
+        })
+    }
+
+    fn sample_db(&self) -> Result<&Connection, KvError> {
+        if let Some(samples) = self.samples.get() {
+            return Ok(samples);
+        }
+
+        let mut samples =
+            Connection::open(self.path.with_extension("samples.db")).map_err(KvError::Open)?;
+        samples.busy_timeout(BUSY_TIMEOUT)?;
+        // Samples are written every few seconds, WAL without a sync per commit
+        // spares the disk and only risks the last ones on power loss
+        samples.pragma_update(None, "journal_mode", "WAL")?;
+        samples.pragma_update(None, "synchronous", "NORMAL")?;
+        migrate(&mut samples, SAMPLE_MIGRATIONS)?;
+
+        Ok(self.samples.get_or_init(|| samples))
+    }
+}
+
+impl KvStore for SqliteStore {
+    fn put(&self, key: &str, value: Value, expires_at: Option<i64>) -> Result<(), KvError> {
+        let (column, value): (&str, rusqlite::types::Value) = match value {
+            Value::Bool(value) => ("bool", value.into()),
+            Value::Text(value) => ("text", value.into()),
+            Value::I64(value) => ("i64", value.into()),
+            Value::F64(value) => ("f64", value.into()),
+            Value::Json(value) => ("json", value.to_string().into()),
+        };
+
+        // An expired row must not lend its other columns to the new value
+        self.connection.execute(
+            "DELETE FROM dictionary WHERE key = ?1 AND expires_at <= ?2",
+            rusqlite::params![key, now()],
+        )?;
+        self.connection.execute(
+            &format!(
+                "INSERT INTO dictionary (key, {column}, expires_at) VALUES (?1, ?2, ?3)
+                ON CONFLICT DO UPDATE SET {column} = ?2, expires_at = ?3"
+            ),
+            rusqlite::params![key, value, expires_at],
+        )?;
+        Ok(())
+    }
+
+    fn entry(&self, key: &str) -> Result<Entry, KvError> {
+        let mut stmt = self.connection.prepare(
+            "SELECT bool, text, i64, f64, json FROM dictionary WHERE key = ?1
+                AND (expires_at IS NULL OR expires_at > ?2) LIMIT 1",
+        )?;
+
+        Ok(stmt.query_row(rusqlite::params![key, now()], |row| entry(row, 0))?)
+    }
+
+    fn expires_at(&self, key: &str) -> Result<Option<i64>, KvError> {
+        let mut stmt = self.connection.prepare(
+            "SELECT expires_at FROM dictionary WHERE key = ?1
+                AND (expires_at IS NULL OR expires_at > ?2) LIMIT 1",
+        )?;
+
+        Ok(stmt.query_row(rusqlite::params![key, now()], |row| row.get(0))?)
+    }
+
+    fn del(&self, key: &str) -> Result<(), KvError> {
+        self.connection.execute(
+            "DELETE FROM dictionary WHERE key = ?1",
+            rusqlite::params![key],
+        )?;
+        Ok(())
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Entry)>, KvError> {
+        let mut stmt = self.connection.prepare(
+            "SELECT key, bool, text, i64, f64, json FROM dictionary
+                WHERE substr(key, 1, length(?1)) = ?1
+                AND (expires_at IS NULL OR expires_at > ?2) ORDER BY key",
+        )?;
+
+        let rows = stmt.query_map(rusqlite::params![prefix, now()], |row| {
+            Ok((row.get(0)?, entry(row, 1)?))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn del_prefix(&self, prefix: &str) -> Result<usize, KvError> {
+        Ok(self.connection.execute(
+            "DELETE FROM dictionary WHERE substr(key, 1, length(?1)) = ?1",
+            rusqlite::params![prefix],
+        )?)
+    }
+
+    fn sweep(&self) -> Result<(), KvError> {
+        self.connection.execute(
+            "DELETE FROM dictionary WHERE expires_at <= ?1",
+            rusqlite::params![now()],
+        )?;
+        Ok(())
+    }
+
+    fn begin(&self) -> Result<bool, KvError> {
+        if !self.connection.is_autocommit() {
+            return Ok(false);
+        }
+
+        // Immediate so that reads inside the transaction can't be invalidated by another writer
+        self.connection.execute_batch("BEGIN IMMEDIATE")?;
+        Ok(true)
+    }
+
+    fn commit(&self) -> Result<(), KvError> {
+        self.connection.execute_batch("COMMIT")?;
+        Ok(())
+    }
+
+    fn rollback(&self) -> Result<(), KvError> {
+        self.connection.execute_batch("ROLLBACK")?;
+        Ok(())
+    }
+
+    fn path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+
+    fn record_sample(&self, series: &str, sample: Sample) -> Result<(), KvError> {
+        // One commit for both rows
+        let tx = self.sample_db()?.unchecked_transaction()?;
+        tx.execute(
+            "INSERT INTO series (name) VALUES (?1) ON CONFLICT DO NOTHING",
+            rusqlite::params![series],
+        )?;
+        tx.execute(
+            "INSERT OR REPLACE INTO samples (series, at, value)
+                SELECT id, ?2, ?3 FROM series WHERE name = ?1",
+            rusqlite::params![series, sample.at, sample.value],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn samples(&self, series: &str, since: i64) -> Result<Vec<Sample>, KvError> {
+        let mut stmt = self.sample_db()?.prepare(
+            "SELECT at, value FROM samples
+                WHERE series = (SELECT id FROM series WHERE name = ?1) AND at >= ?2
+                ORDER BY at",
+        )?;
+
+        let rows = stmt.query_map(rusqlite::params![series, since], |row| {
+            Ok(Sample {
+                at: row.get(0)?,
+                value: row.get(1)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn compact_samples(&self, before: i64, bucket: i64) -> Result<(), KvError> {
+        let tx = self.sample_db()?.unchecked_transaction()?;
+
+        // Only buckets that still hold more than one sample, or one off the bucket start,
+        // so already compacted history isn't rewritten every time
+        let buckets = {
+            let mut stmt = tx.prepare(
+                "SELECT series, at / ?2 * ?2 AS start, avg(value) FROM samples
+                    WHERE at < ?1 GROUP BY series, start
+                    HAVING count(*) > 1 OR min(at) != start",
+            )?;
+            let rows = stmt.query_map(rusqlite::params![before, bucket], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, f64>(2)?,
+                ))
+            })?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+
+        for (series, start, value) in buckets {
+            tx.execute(
+                "DELETE FROM samples WHERE series = ?1 AND at >= ?2 AND at < ?3",
+                rusqlite::params![series, start, start + bucket],
+            )?;
+            tx.execute(
+                "INSERT INTO samples (series, at, value) VALUES (?1, ?2, ?3)",
+                rusqlite::params![series, start, value],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn prune_samples(&self, before: i64) -> Result<(), KvError> {
+        self.sample_db()?.execute(
+            "DELETE FROM samples WHERE at < ?1",
+            rusqlite::params![before],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::tests::TempDir;
+    use crate::kv::{Kv, MemoryStore};
+    use rusqlite::ffi;
+    use std::fs;
+    use std::rc::Rc;
+
+    fn user_version(path: &Path) -> usize {
+        Connection::open(path)
+            .unwrap()
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn errors() {
+        let busy = rusqlite::Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_BUSY), None);
+        assert!(matches!(KvError::from(busy), KvError::Busy));
+        assert!(matches!(
+            KvError::from(rusqlite::Error::QueryReturnedNoRows),
+            KvError::NotFound
+        ));
+
+        let connection = Connection::open_in_memory().unwrap();
+        let missing = connection
+            .execute("DELETE FROM dictionary", [])
+            .unwrap_err();
+        assert!(matches!(KvError::from(missing), KvError::SchemaMismatch(_)));
+        let syntax = connection.execute("DELETE", []).unwrap_err();
+        assert!(matches!(KvError::from(syntax), KvError::Sqlite(_)));
+    }
+
+    #[test]
+    fn migrations() {
+        let dir = TempDir::new("migrations");
+        let path = dir.0.join("rice.db");
+
+        // A database from before versioning
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE dictionary (key TEXT PRIMARY KEY, bool BOOLEAN, text TEXT,
+                    i64 INTEGER, f64 DECIMAL);
+                INSERT INTO dictionary (key, bool) VALUES ('recording', 1);",
+            )
+            .unwrap();
+
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.entry("recording").unwrap().bool, Some(true));
+        store
+            .put("recording", Value::Json(1.into()), Some(i64::MAX))
+            .unwrap();
+        drop(store);
+        assert_eq!(user_version(&path), MIGRATIONS.len());
+
+        // Opening an up to date database doesn't write to it, not even its change counter
+        let written = fs::read(&path).unwrap();
+        SqliteStore::open(&path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), written);
+
+        Connection::open(&path)
+            .unwrap()
+            .pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        assert!(matches!(
+            SqliteStore::open(&path),
+            Err(KvError::SchemaMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn samples_open_lazily() {
+        let dir = TempDir::new("lazy-samples");
+        let store = SqliteStore::open(&dir.0.join("rice.db")).unwrap();
+        store.put("recording", Value::Bool(true), None).unwrap();
+        let files = || fs::read_dir(&dir.0).unwrap().count();
+        assert_eq!(files(), 1);
+
+        store
+            .record_sample("power", Sample { at: 1, value: 2.0 })
+            .unwrap();
+        assert!(dir.0.join("rice.samples.db").exists());
+    }
+
+    #[test]
+    fn failed_commit_rolls_back() {
+        let dir = TempDir::new("failed-commit");
+        let path = dir.0.join("rice.db");
+        let store = SqliteStore::open(&path).unwrap();
+        store.connection.busy_timeout(Duration::ZERO).unwrap();
+        let kv = Kv::with_store(Rc::new(store)).unwrap();
+
+        // Another process reading keeps the commit from getting its exclusive lock
+        let reader = Connection::open(&path).unwrap();
+        reader
+            .execute_batch("BEGIN; SELECT count(*) FROM dictionary;")
+            .unwrap();
+        let result = kv.transaction(|tx| tx.put_bool("recording", true));
+        assert!(matches!(result, Err(KvError::Busy)));
+        reader.execute_batch("COMMIT").unwrap();
+
+        kv.transaction(|tx| tx.put_bool("recording", true)).unwrap();
+        assert_eq!(
+            SqliteStore::open(&path)
+                .unwrap()
+                .entry("recording")
+                .unwrap()
+                .bool,
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn compaction_matches_memory() {
+        let dir = TempDir::new("compaction-agreement");
+        let sqlite = SqliteStore::open(&dir.0.join("rice.db")).unwrap();
+        let journal: String = sqlite
+            .sample_db()
+            .unwrap()
+            .pragma_query_value(None, "journal_mode", |row| row.get(0))
+            .unwrap();
+        assert_eq!(journal, "wal");
+
+        let stores = [
+            Kv::with_store(Rc::new(MemoryStore::new())).unwrap(),
+            Kv::with_store(Rc::new(sqlite)).unwrap(),
+        ];
+        let now = crate::kv::now();
+
+        // Uneven samples over eight days, compacted twice so that the second run
+        // also averages new samples into buckets compacted by the first
+        let compacted = stores.map(|kv| {
+            for round in 0..2 {
+                for i in 0..400 {
+                    let at = now - (i * 7919 + round * 31) % (8 * 24 * 60 * 60);
+                    let series = if i % 3 == 0 { "power" } else { "capacity" };
+                    let value = (i % 23) as f64 * 0.5 + round as f64;
+                    kv.store
+                        .record_sample(series, Sample { at, value })
+                        .unwrap();
+                }
+                kv.compact_samples().unwrap();
+            }
+            ["power", "capacity"].map(|series| kv.samples(series, 0).unwrap())
+        });
+
+        let [memory, sqlite] = compacted;
+        for (memory, sqlite) in memory.iter().zip(&sqlite) {
+            assert!(memory.len() > 10);
+            assert_eq!(memory.len(), sqlite.len());
+            for (m, s) in memory.iter().zip(sqlite) {
+                assert_eq!(m.at, s.at);
+                assert!((m.value - s.value).abs() < 1e-9, "{:?} != {:?}", m, s);
+            }
+        }
+    }
+}
@@ -1,4 +1,7 @@
+pub mod config;
+pub mod emit;
 pub mod kv;
 pub mod pulse;
+pub mod template;
 pub mod types;
 pub mod utils;
@@ -1,124 +1,1277 @@
+use crate::emit::{self, Emitter};
+use futures::{Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
 use std::error::Error;
-use tokio::process::Command;
+use std::str::FromStr;
+use std::{fmt, io};
 
-pub struct Source {
+mod runner;
+
+pub use runner::{CommandOutput, CommandRunner, FixtureRunner, LineStream, ProcessRunner};
+
+/// pactl prints some ids as strings and unset ones as "n/a", so accept all of those.
+fn lenient_index<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Number(n) => n.as_u64().and_then(|n| u32::try_from(n).ok()),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    })
+}
+
+#[derive(Deserialize)]
+struct RawChannelVolume {
+    value: u32,
+    value_percent: String,
+    db: String,
+}
+
+/// The volume of a single channel. `value` is on PulseAudio's scale, where 65536 is 100%.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(try_from = "RawChannelVolume")]
+pub struct ChannelVolume {
+    pub value: u32,
+    pub percent: u32,
+    pub db: f64,
+}
+
+impl TryFrom<RawChannelVolume> for ChannelVolume {
+    type Error = String;
+
+    fn try_from(raw: RawChannelVolume) -> Result<Self, Self::Error> {
+        let percent = raw.value_percent.trim().trim_end_matches('%');
+        let db = raw.db.trim().trim_end_matches("dB").trim();
+
+        Ok(ChannelVolume {
+            value: raw.value,
+            percent: percent
+                .parse()
+                .map_err(|_| format!("invalid volume percent {:?}", raw.value_percent))?,
+            // Silence is "-inf dB", which f64 parses
+            db: db
+                .parse()
+                .map_err(|_| format!("invalid volume dB {:?}", raw.db))?,
+        })
+    }
+}
+
+/// Volume of every channel, keyed by channel name such as `front-left`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct Volume {
+    pub channels: BTreeMap<String, ChannelVolume>,
+}
+
+impl Volume {
+    /// Average over all channels, on PulseAudio's scale.
+    pub fn value(&self) -> u32 {
+        if self.channels.is_empty() {
+            return 0;
+        }
+
+        let sum: u64 = self.channels.values().map(|c| c.value as u64).sum();
+        (sum / self.channels.len() as u64) as u32
+    }
+
+    /// Average over all channels, rounded like pactl rounds it.
+    pub fn percent(&self) -> u32 {
+        ((self.value() as f64 * 100.0) / 65536.0).round() as u32
+    }
+
+    /// Average over all channels, using PulseAudio's cubic volume curve.
+    pub fn db(&self) -> f64 {
+        60.0 * (self.value() as f64 / 65536.0).log10()
+    }
+
+    /// CSS class for the average volume: low, medium, high or over-amplified past 100%.
+    pub fn level(&self) -> &'static str {
+        match self.percent() {
+            0..34 => "low",
+            34..67 => "medium",
+            67..=100 => "high",
+            _ => "over-amplified",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum Availability {
+    #[serde(rename = "available")]
+    Available,
+    #[serde(rename = "not available")]
+    NotAvailable,
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+/// A jack or connector of a device, such as the headphone jack of a sound card.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Port {
     pub name: String,
-    pub mute: bool,
+    pub description: String,
+    /// Speaker, Headphones, Headset, HDMI, Mic... Empty with pactl before 16
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub priority: u32,
+    pub availability: Availability,
 }
 
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
 pub struct Sink {
+    pub index: u32,
+    pub name: String,
+    pub description: String,
+    pub driver: String,
+    pub state: String,
+    pub mute: bool,
+    /// Channel names in pactl's order, such as `front-left,front-right`
+    pub channel_map: String,
+    pub volume: Volume,
+    pub base_volume: ChannelVolume,
+    pub balance: f64,
+    pub monitor_source: String,
+    pub ports: Vec<Port>,
+    pub active_port: Option<String>,
+    pub properties: BTreeMap<String, String>,
+}
+
+impl Sink {
+    pub fn active_port(&self) -> Option<&Port> {
+        let active = self.active_port.as_deref()?;
+        self.ports.iter().find(|p| p.name == active)
+    }
+
+    /// `device.form_factor`, such as internal, headphone, headset or tv.
+    pub fn form_factor(&self) -> Option<&str> {
+        self.properties
+            .get("device.form_factor")
+            .map(String::as_str)
+    }
+
+    /// `device.bus`, such as pci, usb or bluetooth.
+    pub fn bus(&self) -> Option<&str> {
+        self.properties.get("device.bus").map(String::as_str)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Source {
+    pub index: u32,
     pub name: String,
+    pub description: String,
+    pub driver: String,
+    pub state: String,
     pub mute: bool,
+    /// Channel names in pactl's order, such as `front-left,front-right`
+    pub channel_map: String,
+    pub volume: Volume,
+    pub base_volume: ChannelVolume,
+    pub balance: f64,
+    pub monitor_of_sink: Option<String>,
+    pub properties: BTreeMap<String, String>,
 }
 
-pub async fn list_sinks() -> Result<Vec<Sink>, Box<dyn Error>> {
-    let output = Command::new("pactl")
-        .arg("list")
-        .arg("sinks")
-        .output()
-        .await?;
-    let text = String::from_utf8_lossy(&output.stdout);
+/// What the device picker needs of sinks and sources.
+pub trait DeviceInfo {
+    fn index(&self) -> u32;
 
-    // Parse pactl list sources into Source structs
-    let mut sources = Vec::new();
+    fn name(&self) -> &str;
 
-    let mut name = "".to_string();
+    fn description(&self) -> &str;
+}
 
-    for line in text.lines() {
-        let line = line.trim();
-        if line.starts_with("Name: ") {
-            name = line.split_whitespace().last().unwrap().to_string();
-        }
+impl DeviceInfo for Sink {
+    fn index(&self) -> u32 {
+        self.index
+    }
 
-        if line.starts_with("Mute: ") {
-            sources.push(Sink {
-                name: name.clone(),
-                mute: line.contains("Mute: yes"),
-            });
-        }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+}
+
+impl DeviceInfo for Source {
+    fn index(&self) -> u32 {
+        self.index
+    }
+
+    fn name(&self) -> &str {
+        &self.name
     }
 
-    Ok(sources)
+    fn description(&self) -> &str {
+        &self.description
+    }
 }
 
-pub async fn list_sources() -> Result<Vec<Source>, Box<dyn Error>> {
-    let output = Command::new("pactl")
-        .arg("list")
-        .arg("sources")
-        .output()
-        .await?;
-    let text = String::from_utf8_lossy(&output.stdout);
+/// A line for a dmenu-style launcher, which `find` takes back.
+pub fn menu_entry(device: &impl DeviceInfo) -> String {
+    format!("{}: {}", device.index(), device.description())
+}
 
-    // Parse pactl list sources into Source structs
-    let mut sources = Vec::new();
+/// Finds a device by name, index, or a line from `menu_entry`.
+pub fn find<'a, D: DeviceInfo>(devices: &'a [D], choice: &str) -> Option<&'a D> {
+    let choice = choice.trim();
+    let key = choice.split_once(": ").map_or(choice, |(index, _)| index);
+    devices
+        .iter()
+        .find(|d| d.name() == key || d.index().to_string() == key)
+}
 
-    let mut name = "".to_string();
+/// The device after the one named `current`, wrapping around, or the first
+/// one when `current` isn't listed.
+pub fn next<'a, D: DeviceInfo>(devices: &'a [D], current: &str) -> Option<&'a D> {
+    let next = devices
+        .iter()
+        .position(|d| d.name() == current)
+        .map_or(0, |i| (i + 1) % devices.len());
+    devices.get(next)
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SinkInput {
+    pub index: u32,
+    pub driver: String,
+    #[serde(deserialize_with = "lenient_index")]
+    pub client: Option<u32>,
+    #[serde(deserialize_with = "lenient_index")]
+    pub sink: Option<u32>,
+    pub corked: bool,
+    pub mute: bool,
+    pub properties: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SourceOutput {
+    pub index: u32,
+    pub driver: String,
+    #[serde(deserialize_with = "lenient_index")]
+    pub client: Option<u32>,
+    #[serde(deserialize_with = "lenient_index")]
+    pub source: Option<u32>,
+    pub corked: bool,
+    pub mute: bool,
+    /// "n/a" for streams without a resampler, like module-loopback's
+    pub resample_method: Option<String>,
+    pub properties: BTreeMap<String, String>,
+}
+
+impl SourceOutput {
+    pub fn application_name(&self) -> Option<&str> {
+        self.properties.get("application.name").map(String::as_str)
+    }
+
+    pub fn binary(&self) -> Option<&str> {
+        self.properties
+            .get("application.process.binary")
+            .map(String::as_str)
+    }
+
+    pub fn pid(&self) -> Option<u32> {
+        self.properties.get("application.process.id")?.parse().ok()
+    }
+
+    /// Streams that only read levels for a meter, like pavucontrol's. PulseAudio marks them
+    /// with the "peaks" resampler, PipeWire with properties.
+    fn is_peak_detect(&self) -> bool {
+        self.resample_method.as_deref() == Some("peaks")
+            || self
+                .properties
+                .get("resample.peaks")
+                .is_some_and(|v| v == "true")
+            || self
+                .properties
+                .get("stream.monitor")
+                .is_some_and(|v| v == "true")
+    }
+}
+
+/// Keeps the streams that actually record, dropping level meters and
+/// streams that read a sink's monitor rather than a microphone.
+pub fn recording(outputs: Vec<SourceOutput>, sources: &[Source]) -> Vec<SourceOutput> {
+    outputs
+        .into_iter()
+        .filter(|output| !output.is_peak_detect())
+        .filter(|output| {
+            !sources
+                .iter()
+                .any(|s| Some(s.index) == output.source && s.monitor_of_sink.is_some())
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    pub description: String,
+    pub sinks: u32,
+    pub sources: u32,
+    pub priority: u32,
+    pub available: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Card {
+    pub index: u32,
+    pub name: String,
+    pub driver: String,
+    pub active_profile: Option<String>,
+    pub profiles: BTreeMap<String, Profile>,
+    pub properties: BTreeMap<String, String>,
+}
+
+impl Card {
+    /// `device.description`, such as the headset's model name.
+    pub fn description(&self) -> Option<&str> {
+        self.properties
+            .get("device.description")
+            .map(String::as_str)
+    }
+
+    /// Profiles that can be switched to, best first, leaving out "off".
+    pub fn available_profiles(&self) -> Vec<(&str, &Profile)> {
+        let mut profiles: Vec<_> = self
+            .profiles
+            .iter()
+            .filter(|(name, profile)| profile.available && name.as_str() != "off")
+            .map(|(name, profile)| (name.as_str(), profile))
+            .collect();
+        profiles.sort_by_key(|(_, profile)| std::cmp::Reverse(profile.priority));
+        profiles
+    }
+
+    /// The available profile after the active one, wrapping around.
+    pub fn next_profile(&self) -> Option<&str> {
+        let profiles = self.available_profiles();
+        let active = profiles
+            .iter()
+            .position(|(name, _)| Some(*name) == self.active_profile.as_deref());
+
+        let next = match active {
+            Some(i) => (i + 1) % profiles.len(),
+            None => 0,
+        };
+        profiles.get(next).map(|(name, _)| *name)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ServerInfo {
+    pub server_name: String,
+    pub server_version: String,
+    pub user_name: String,
+    pub host_name: String,
+    pub default_sample_specification: String,
+    pub default_sink_name: String,
+    pub default_source_name: String,
+}
+
+fn snake_case(key: &str) -> String {
+    key.trim().to_lowercase().replace([' ', '-'], "_")
+}
+
+fn text_value(value: &str) -> Value {
+    match value {
+        "yes" => Value::Bool(true),
+        "no" => Value::Bool(false),
+        "n/a" => Value::Null,
+        _ => Value::String(value.to_string()),
+    }
+}
+
+/// Parses a `Profiles:` line such as
+/// `output:analog-stereo: Analog Stereo Output (sinks: 1, sources: 0, priority: 6500, available: yes)`.
+fn parse_profile(line: &str) -> Option<(String, Value)> {
+    let (name, rest) = line.split_once(": ")?;
+    let (description, details) = rest.rsplit_once(" (")?;
+
+    let mut profile = Map::new();
+    profile.insert("description".into(), description.into());
+
+    for detail in details.trim_end_matches(')').split(", ") {
+        let Some((key, value)) = detail.split_once(": ") else {
+            continue;
+        };
+        let value = match value.parse::<u32>() {
+            Ok(n) => n.into(),
+            Err(_) => text_value(value),
+        };
+        profile.insert(key.to_string(), value);
+    }
+
+    Some((name.to_string(), Value::Object(profile)))
+}
+
+/// Parses a `Ports:` line such as
+/// `analog-output-speaker: Speakers (type: Speaker, priority: 10000, availability group: Legacy 1, availability unknown)`.
+fn parse_port(line: &str) -> Option<Value> {
+    let (name, rest) = line.split_once(": ")?;
+    let (description, details) = rest.rsplit_once(" (")?;
+
+    let mut port = Map::new();
+    port.insert("name".into(), name.into());
+    port.insert("description".into(), description.into());
+
+    for detail in details.trim_end_matches(')').split(", ") {
+        match detail.split_once(": ") {
+            Some(("type", value)) => port.insert("type".into(), value.into()),
+            Some(("priority", value)) => {
+                port.insert("priority".into(), value.parse::<u32>().ok()?.into())
+            }
+            Some(_) => None,
+            // The availability has no key
+            None => port.insert("availability".into(), detail.into()),
+        };
+    }
+
+    Some(Value::Object(port))
+}
+
+/// Parses a volume such as `65536 / 100% / 0.00 dB`.
+fn parse_channel_volume(text: &str) -> Option<Value> {
+    let mut parts = text.split('/').map(str::trim);
+    let value = parts.next()?.parse::<u32>().ok()?;
+
+    let mut volume = Map::new();
+    volume.insert("value".into(), value.into());
+    volume.insert("value_percent".into(), parts.next()?.into());
+    volume.insert("db".into(), parts.next()?.into());
+    Some(Value::Object(volume))
+}
+
+/// Parses a `Volume:` line such as
+/// `front-left: 65536 / 100% / 0.00 dB,   front-right: 65536 / 100% / 0.00 dB`.
+fn parse_volume(text: &str) -> Option<Value> {
+    let mut volume = Map::new();
+    for channel in text.split(',') {
+        let (name, channel) = channel.split_once(':')?;
+        volume.insert(name.trim().to_string(), parse_channel_volume(channel)?);
+    }
+    Some(Value::Object(volume))
+}
+
+/// Turns C-locale `pactl list` output into the shape of `pactl -f json list`,
+/// as far as the structs above read it.
+fn parse_list(text: &str) -> Vec<Value> {
+    let mut items: Vec<Map<String, Value>> = Vec::new();
+    let mut section = String::new();
 
     for line in text.lines() {
+        let depth = line.chars().take_while(|c| *c == '\t').count();
         let line = line.trim();
-        if line.starts_with("Name: ") {
-            name = line.split_whitespace().last().unwrap().to_string();
+        if line.is_empty() {
+            continue;
         }
 
-        if line.starts_with("Mute: ") {
-            sources.push(Source {
-                name: name.clone(),
-                mute: line.contains("Mute: yes"),
-            });
+        // Every item starts with a header like "Sink #56"
+        if depth == 0 {
+            let mut item = Map::new();
+            if let Some(index) = line
+                .rsplit_once('#')
+                .and_then(|(_, i)| i.parse::<u32>().ok())
+            {
+                item.insert("index".into(), index.into());
+            }
+            items.push(item);
+            continue;
+        }
+
+        let Some(item) = items.last_mut() else {
+            continue;
+        };
+
+        if depth == 1 {
+            // Volume is followed by a "balance 0.00" line
+            if let Some(balance) = line.strip_prefix("balance ")
+                && let Ok(balance) = balance.parse::<f64>()
+            {
+                item.insert("balance".into(), balance.into());
+                continue;
+            }
+
+            // Other continuation lines of a multi-line value have no key
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+
+            let key = snake_case(key);
+            let value = value.trim();
+            if value.is_empty() {
+                section = key;
+                continue;
+            }
+
+            section.clear();
+            let value = match key.as_str() {
+                "volume" => parse_volume(value),
+                "base_volume" => parse_channel_volume(value),
+                _ => Some(text_value(value)),
+            };
+            if let Some(value) = value {
+                item.insert(key, value);
+            }
+            continue;
+        }
+
+        if depth == 2 && section == "properties" {
+            if let Some((key, value)) = line.split_once(" = ") {
+                item.entry("properties")
+                    .or_insert_with(|| Value::Object(Map::new()))
+                    .as_object_mut()
+                    .unwrap()
+                    .insert(key.to_string(), value.trim_matches('"').into());
+            }
+        } else if depth == 2
+            && section == "ports"
+            && let Some(port) = parse_port(line)
+        {
+            item.entry("ports")
+                .or_insert_with(|| Value::Array(Vec::new()))
+                .as_array_mut()
+                .unwrap()
+                .push(port);
+        } else if depth == 2
+            && section == "profiles"
+            && let Some((name, profile)) = parse_profile(line)
+        {
+            item.entry("profiles")
+                .or_insert_with(|| Value::Object(Map::new()))
+                .as_object_mut()
+                .unwrap()
+                .insert(name, profile);
         }
     }
 
-    Ok(sources)
+    items.into_iter().map(Value::Object).collect()
 }
 
-pub async fn default_sink() -> Result<String, Box<dyn Error>> {
-    let output = Command::new("pactl").arg("info").output().await?;
-    let text = String::from_utf8_lossy(&output.stdout);
+/// Turns C-locale `pactl info` output into the shape of `pactl -f json info`.
+fn parse_info(text: &str) -> Value {
+    let mut info = Map::new();
 
     for line in text.lines() {
-        if line.starts_with("Default Sink:") {
-            return Ok(line.split_whitespace().last().unwrap().to_string());
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+
+        let key = match snake_case(key).as_str() {
+            "default_sink" => "default_sink_name".to_string(),
+            "default_source" => "default_source_name".to_string(),
+            key => key.to_string(),
+        };
+        info.insert(key, text_value(value.trim()));
+    }
+
+    Value::Object(info)
+}
+
+fn parse_list_value(text: &str) -> Value {
+    Value::Array(parse_list(text))
+}
+
+/// Which kind of device a control function acts on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Device {
+    Sink,
+    Source,
+}
+
+impl Device {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Device::Sink => "sink",
+            Device::Source => "source",
         }
     }
 
-    Err("No default source found".into())
+    /// The name pactl resolves to the current default device.
+    pub fn default_name(&self) -> &'static str {
+        match self {
+            Device::Sink => "@DEFAULT_SINK@",
+            Device::Source => "@DEFAULT_SOURCE@",
+        }
+    }
 }
 
-pub async fn default_source() -> Result<String, Box<dyn Error>> {
-    let output = Command::new("pactl").arg("info").output().await?;
-    let text = String::from_utf8_lossy(&output.stdout);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    New,
+    Change,
+    Remove,
+}
 
-    for line in text.lines() {
-        if line.starts_with("Default Source:") {
-            return Ok(line.split_whitespace().last().unwrap().to_string());
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Facility {
+    Sink,
+    Source,
+    SinkInput,
+    SourceOutput,
+    Module,
+    Client,
+    SampleCache,
+    Server,
+    Card,
+}
+
+/// One line of `pactl subscribe`, such as `Event 'change' on sink #56`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PulseEvent {
+    pub kind: EventKind,
+    pub facility: Facility,
+    /// None for events on the server itself, which have no index
+    pub index: Option<u32>,
+}
+
+#[derive(Debug)]
+pub enum SubscribeError {
+    Io(io::Error),
+    Parse(String),
+}
+
+impl fmt::Display for SubscribeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubscribeError::Io(e) => write!(f, "failed to read pactl subscribe: {}", e),
+            SubscribeError::Parse(line) => write!(f, "unexpected pactl subscribe line: {}", line),
         }
     }
+}
+
+impl Error for SubscribeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SubscribeError::Io(e) => Some(e),
+            SubscribeError::Parse(_) => None,
+        }
+    }
+}
+
+impl FromStr for PulseEvent {
+    type Err = SubscribeError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let parse = || {
+            let rest = line.trim().strip_prefix("Event '")?;
+            let (kind, rest) = rest.split_once("' on ")?;
+            let (facility, index) = rest.split_once(" #")?;
+
+            let kind = match kind {
+                "new" => EventKind::New,
+                "change" => EventKind::Change,
+                "remove" => EventKind::Remove,
+                _ => return None,
+            };
+            let facility = match facility {
+                "sink" => Facility::Sink,
+                "source" => Facility::Source,
+                "sink-input" => Facility::SinkInput,
+                "source-output" => Facility::SourceOutput,
+                "module" => Facility::Module,
+                "client" => Facility::Client,
+                "sample-cache" => Facility::SampleCache,
+                "server" => Facility::Server,
+                "card" => Facility::Card,
+                _ => return None,
+            };
+            // PA_INVALID_INDEX, printed unsigned
+            let index = match index.parse::<u32>().ok()? {
+                u32::MAX => None,
+                index => Some(index),
+            };
+
+            Some(PulseEvent {
+                kind,
+                facility,
+                index,
+            })
+        };
+
+        parse().ok_or_else(|| SubscribeError::Parse(line.to_string()))
+    }
+}
 
-    Err("No default source found".into())
+/// Talks to the sound server through pactl, run by `R`.
+#[derive(Debug, Default)]
+pub struct Pulse<R: CommandRunner = ProcessRunner> {
+    runner: R,
 }
 
-pub async fn is_output_muted() -> Result<bool, Box<dyn Error>> {
-    let sink = default_sink().await?;
-    let sinks = list_sinks().await?;
+impl Pulse {
+    pub fn new() -> Pulse {
+        Pulse::with_runner(ProcessRunner)
+    }
+}
+
+impl<R: CommandRunner> Pulse<R> {
+    pub fn with_runner(runner: R) -> Pulse<R> {
+        Pulse { runner }
+    }
+
+    pub fn runner(&self) -> &R {
+        &self.runner
+    }
+
+    /// Runs `pactl -f json <args>`. pactl before 16 has no JSON output, and some releases
+    /// print broken JSON, so fall back to parsing the C-locale text form.
+    async fn pactl<T: DeserializeOwned>(
+        &self,
+        args: &[&str],
+        parse: fn(&str) -> Value,
+    ) -> Result<T, Box<dyn Error>> {
+        let output = self
+            .runner
+            .output("pactl", &[&["-f", "json"], args].concat())
+            .await?;
+
+        if output.success
+            && let Ok(value) = serde_json::from_slice(&output.stdout)
+        {
+            return Ok(value);
+        }
+
+        let output = self.runner.output("pactl", args).await?;
+        if !output.success {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().into());
+        }
+
+        Ok(serde_json::from_value(parse(&String::from_utf8_lossy(
+            &output.stdout,
+        )))?)
+    }
+
+    /// Runs a pactl command that changes state and prints nothing on success.
+    async fn pactl_set(&self, args: &[&str]) -> Result<(), Box<dyn Error>> {
+        let output = self.runner.output("pactl", args).await?;
+        if !output.success {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().into());
+        }
+
+        Ok(())
+    }
+
+    pub async fn list_sinks(&self) -> Result<Vec<Sink>, Box<dyn Error>> {
+        self.pactl(&["list", "sinks"], parse_list_value).await
+    }
+
+    pub async fn list_sources(&self) -> Result<Vec<Source>, Box<dyn Error>> {
+        self.pactl(&["list", "sources"], parse_list_value).await
+    }
+
+    pub async fn list_sink_inputs(&self) -> Result<Vec<SinkInput>, Box<dyn Error>> {
+        self.pactl(&["list", "sink-inputs"], parse_list_value).await
+    }
+
+    pub async fn list_source_outputs(&self) -> Result<Vec<SourceOutput>, Box<dyn Error>> {
+        self.pactl(&["list", "source-outputs"], parse_list_value)
+            .await
+    }
+
+    /// Streams recording from a microphone or other real source, see `recording`.
+    pub async fn recording_streams(&self) -> Result<Vec<SourceOutput>, Box<dyn Error>> {
+        let outputs = self.list_source_outputs().await?;
+        Ok(recording(outputs, &self.list_sources().await?))
+    }
+
+    pub async fn list_cards(&self) -> Result<Vec<Card>, Box<dyn Error>> {
+        self.pactl(&["list", "cards"], parse_list_value).await
+    }
+
+    pub async fn server_info(&self) -> Result<ServerInfo, Box<dyn Error>> {
+        self.pactl(&["info"], parse_info).await
+    }
+
+    pub async fn default_sink(&self) -> Result<String, Box<dyn Error>> {
+        let info = self.server_info().await?;
+        if info.default_sink_name.is_empty() {
+            return Err("No default sink found".into());
+        }
+
+        Ok(info.default_sink_name)
+    }
+
+    pub async fn default_source(&self) -> Result<String, Box<dyn Error>> {
+        let info = self.server_info().await?;
+        if info.default_source_name.is_empty() {
+            return Err("No default source found".into());
+        }
+
+        Ok(info.default_source_name)
+    }
+
+    /// The default sink, if it's still in the list by the time that's read.
+    pub async fn output_device(&self) -> Result<Option<Sink>, Box<dyn Error>> {
+        let sink = self.default_sink().await?;
+        Ok(self
+            .list_sinks()
+            .await?
+            .into_iter()
+            .find(|s| s.name == sink))
+    }
+
+    /// The default source, if it's still in the list by the time that's read.
+    pub async fn input_device(&self) -> Result<Option<Source>, Box<dyn Error>> {
+        let source = self.default_source().await?;
+        Ok(self
+            .list_sources()
+            .await?
+            .into_iter()
+            .find(|s| s.name == source))
+    }
+
+    pub async fn is_output_muted(&self) -> Result<bool, Box<dyn Error>> {
+        Ok(self.output_device().await?.is_none_or(|s| s.mute))
+    }
+
+    pub async fn is_input_muted(&self) -> Result<bool, Box<dyn Error>> {
+        Ok(self.input_device().await?.is_none_or(|s| s.mute))
+    }
+
+    /// Makes `name` the default sink. With `move_streams`, everything already playing moves there too.
+    pub async fn set_default_sink(
+        &self,
+        name: &str,
+        move_streams: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        self.pactl_set(&["set-default-sink", name]).await?;
+
+        if move_streams {
+            for input in self.list_sink_inputs().await? {
+                // Streams that opted out of moving fail, leave them where they are
+                let _ = self
+                    .pactl_set(&["move-sink-input", &input.index.to_string(), name])
+                    .await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Makes `name` the default source. With `move_streams`, everything recording moves there too,
+    /// apart from level meters and streams reading a monitor.
+    pub async fn set_default_source(
+        &self,
+        name: &str,
+        move_streams: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        self.pactl_set(&["set-default-source", name]).await?;
+
+        if move_streams {
+            for output in self.recording_streams().await? {
+                // Streams that opted out of moving fail, leave them where they are
+                let _ = self
+                    .pactl_set(&["move-source-output", &output.index.to_string(), name])
+                    .await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `card` is a card name or index, `profile` a key of `Card::profiles`.
+    pub async fn set_card_profile(&self, card: &str, profile: &str) -> Result<(), Box<dyn Error>> {
+        self.pactl_set(&["set-card-profile", card, profile]).await
+    }
+
+    /// Volume and channel map of the device with this name or index.
+    async fn volume(&self, device: Device, name: &str) -> Result<(Volume, String), Box<dyn Error>> {
+        let name = match (device, name == device.default_name()) {
+            (Device::Sink, true) => self.default_sink().await?,
+            (Device::Source, true) => self.default_source().await?,
+            _ => name.to_string(),
+        };
+
+        let volume = match device {
+            Device::Sink => self
+                .list_sinks()
+                .await?
+                .into_iter()
+                .find(|s| s.name == name || s.index.to_string() == name)
+                .map(|s| (s.volume, s.channel_map)),
+            Device::Source => self
+                .list_sources()
+                .await?
+                .into_iter()
+                .find(|s| s.name == name || s.index.to_string() == name)
+                .map(|s| (s.volume, s.channel_map)),
+        };
+
+        volume.ok_or_else(|| format!("No {} named {}", device.as_str(), name).into())
+    }
+
+    /// `name` is a device name, an index, or `device.default_name()`.
+    pub async fn set_mute(
+        &self,
+        device: Device,
+        name: &str,
+        mute: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let command = format!("set-{}-mute", device.as_str());
+        self.pactl_set(&[&command, name, if mute { "1" } else { "0" }])
+            .await
+    }
+
+    pub async fn toggle_mute(&self, device: Device, name: &str) -> Result<(), Box<dyn Error>> {
+        let command = format!("set-{}-mute", device.as_str());
+        self.pactl_set(&[&command, name, "toggle"]).await
+    }
+
+    /// Sets every channel to `percent`, which may be above 100 to over-amplify.
+    pub async fn set_volume(
+        &self,
+        device: Device,
+        name: &str,
+        percent: u32,
+    ) -> Result<(), Box<dyn Error>> {
+        let command = format!("set-{}-volume", device.as_str());
+        self.pactl_set(&[&command, name, &format!("{}%", percent)])
+            .await
+    }
 
-    for s in sinks {
-        if s.name == sink {
-            return Ok(s.mute);
+    /// Moves every channel by `step` percent without the loudest going past `cap`, so
+    /// the balance is kept. A volume that is already above the cap is never raised, but
+    /// isn't pulled down to it either. Returns the new average volume.
+    pub async fn change_volume(
+        &self,
+        device: Device,
+        name: &str,
+        step: i32,
+        cap: u32,
+    ) -> Result<u32, Box<dyn Error>> {
+        let (mut volume, channel_map) = self.volume(device, name).await?;
+        let loudest = volume.channels.values().map(|c| c.value).max().unwrap_or(0) as u64;
+        let delta = step.unsigned_abs() as u64 * 65536 / 100;
+        let cap = cap as u64 * 65536 / 100;
+
+        if step == 0 || (step > 0 && loudest >= cap) || (step < 0 && loudest == 0) {
+            return Ok(volume.percent());
         }
+
+        let command = format!("set-{}-volume", device.as_str());
+        if step > 0 && loudest + delta > cap {
+            // Scale each channel so the loudest lands on the cap
+            for channel in volume.channels.values_mut() {
+                channel.value = (channel.value as u64 * cap / loudest) as u32;
+            }
+
+            let values = channel_map
+                .split(',')
+                .map(|name| volume.channels.get(name).map(|c| c.value.to_string()))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| format!("Unknown channel map {:?}", channel_map))?;
+
+            let mut args = vec![command.as_str(), name];
+            args.extend(values.iter().map(String::as_str));
+            self.pactl_set(&args).await?;
+        } else {
+            // pactl adds a relative step to each channel, clamping at 0
+            for channel in volume.channels.values_mut() {
+                let value = if step > 0 {
+                    channel.value as u64 + delta
+                } else {
+                    (channel.value as u64).saturating_sub(delta)
+                };
+                channel.value = value as u32;
+            }
+
+            // Without "--" a negative step would be taken as an option
+            self.pactl_set(&["--", &command, name, &format!("{:+}%", step)])
+                .await?;
+        }
+
+        Ok(volume.percent())
     }
 
-    Ok(true)
+    /// Streams events from `pactl subscribe`. The stream ends if pactl exits,
+    /// which happens when the server goes away.
+    pub fn subscribe(
+        &self,
+    ) -> Result<impl Stream<Item = Result<PulseEvent, SubscribeError>> + use<R>, SubscribeError>
+    {
+        let lines = self
+            .runner
+            .lines("pactl", &["subscribe"])
+            .map_err(SubscribeError::Io)?;
+
+        Ok(lines.map(|line| match line {
+            Ok(line) => line.parse(),
+            Err(e) => Err(SubscribeError::Io(e)),
+        }))
+    }
 }
 
-pub async fn is_input_muted() -> Result<bool, Box<dyn Error>> {
-    let source = default_source().await?;
-    let sources = list_sources().await?;
+/// Runs `handle` on each of the `events`, e.g. from `Pulse::subscribe`, until they end.
+/// Lines that don't parse are reported and skipped, and while nothing happens the
+/// emitter's last line is repeated whenever it's due.
+pub async fn handle_events<F>(
+    events: impl Stream<Item = Result<PulseEvent, SubscribeError>>,
+    emitter: &mut dyn Emitter,
+    mut handle: F,
+) -> Result<(), Box<dyn Error>>
+where
+    F: AsyncFnMut(&mut dyn Emitter, PulseEvent) -> Result<(), Box<dyn Error>>,
+{
+    let mut events = std::pin::pin!(events);
+
+    loop {
+        let event = tokio::select! {
+            event = events.next() => event,
+            // Nothing changed for a while, repeat the line for --heartbeat
+            _ = emit::sleep_until(emitter.due()) => {
+                emitter.print_again();
+                continue;
+            }
+        };
 
-    for s in sources {
-        if s.name == source {
-            return Ok(s.mute);
+        match event {
+            Some(Ok(event)) => handle(emitter, event).await?,
+            // A line we don't understand shouldn't take the module down
+            Some(Err(e @ SubscribeError::Parse(_))) => eprintln!("{}", e),
+            Some(Err(e)) => return Err(e.into()),
+            None => return Ok(()),
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(true)
+    fn from_json<T: DeserializeOwned>(json: &str) -> T {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn from_text<T: DeserializeOwned>(text: &str) -> Vec<T> {
+        serde_json::from_value(parse_list_value(text)).unwrap()
+    }
+
+    #[test]
+    fn sinks() {
+        for sinks in [
+            from_json::<Vec<Sink>>(include_str!("../tests/fixtures/pulse/pipewire/sinks.json")),
+            from_text::<Sink>(include_str!("../tests/fixtures/pulse/pipewire/sinks.txt")),
+        ] {
+            assert_eq!(sinks.len(), 2);
+            assert_eq!(sinks[0].index, 56);
+            assert_eq!(sinks[0].name, "alsa_output.pci-0000_00_1f.3.analog-stereo");
+            assert_eq!(sinks[0].description, "Built-in Audio Analog Stereo");
+            assert_eq!(sinks[0].state, "SUSPENDED");
+            assert!(!sinks[0].mute);
+            assert_eq!(sinks[0].properties["device.form_factor"], "internal");
+            assert_eq!(sinks[0].volume.channels.len(), 2);
+            assert_eq!(sinks[0].volume.channels["front-left"].percent, 65);
+            assert_eq!(sinks[0].volume.channels["front-left"].db, -11.23);
+            assert_eq!(sinks[0].volume.percent(), 65);
+            assert_eq!(sinks[0].volume.level(), "medium");
+            assert_eq!(sinks[0].base_volume.value, 65536);
+            assert_eq!(sinks[0].balance, 0.0);
+            assert_eq!(sinks[1].name, "bluez_output.00_1B_66_A1_B2_C3.1");
+            assert!(sinks[1].mute);
+            assert_eq!(sinks[1].volume.percent(), 125);
+            assert_eq!(sinks[1].volume.level(), "over-amplified");
+            assert_eq!(sinks[0].ports.len(), 2);
+            assert_eq!(sinks[0].active_port().unwrap().kind, "Speaker");
+            assert_eq!(sinks[0].ports[0].availability, Availability::Unknown);
+            assert_eq!(sinks[0].ports[1].description, "Headphones");
+            assert_eq!(sinks[0].ports[1].priority, 9900);
+            assert_eq!(sinks[0].ports[1].availability, Availability::NotAvailable);
+            assert_eq!(sinks[0].bus(), Some("pci"));
+            assert_eq!(sinks[1].active_port().unwrap().name, "headset-output");
+            assert_eq!(sinks[1].ports[0].availability, Availability::Available);
+            assert_eq!(sinks[1].form_factor(), Some("headset"));
+            assert_eq!(sinks[1].bus(), Some("bluetooth"));
+            assert!((sinks[1].balance + 0.33).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn sources() {
+        for sources in [
+            from_json::<Vec<Source>>(include_str!(
+                "../tests/fixtures/pulse/pipewire/sources.json"
+            )),
+            from_text::<Source>(include_str!("../tests/fixtures/pulse/pipewire/sources.txt")),
+        ] {
+            assert_eq!(sources.len(), 2);
+            assert_eq!(
+                sources[0].monitor_of_sink.as_deref(),
+                Some("alsa_output.pci-0000_00_1f.3.analog-stereo")
+            );
+            assert_eq!(sources[1].name, "alsa_input.pci-0000_00_1f.3.analog-stereo");
+            assert_eq!(sources[1].monitor_of_sink, None);
+            assert!(sources[1].mute);
+            assert_eq!(sources[1].volume.percent(), 40);
+            assert_eq!(sources[1].volume.level(), "medium");
+            assert_eq!(sources[1].base_volume.percent, 10);
+            assert_eq!(sources[1].base_volume.db, -60.0);
+        }
+    }
+
+    #[test]
+    fn sink_inputs() {
+        for inputs in [
+            from_json::<Vec<SinkInput>>(include_str!(
+                "../tests/fixtures/pulse/pipewire/sink-inputs.json"
+            )),
+            from_text::<SinkInput>(include_str!(
+                "../tests/fixtures/pulse/pipewire/sink-inputs.txt"
+            )),
+        ] {
+            assert_eq!(inputs.len(), 1);
+            assert_eq!(inputs[0].index, 91);
+            assert_eq!(inputs[0].sink, Some(56));
+            assert_eq!(inputs[0].client, Some(80));
+            assert!(!inputs[0].corked);
+            assert_eq!(inputs[0].properties["application.name"], "Firefox");
+        }
+    }
+
+    #[test]
+    fn source_outputs() {
+        let sources: Vec<Source> = from_json(include_str!(
+            "../tests/fixtures/pulse/pipewire/sources.json"
+        ));
+
+        for outputs in [
+            from_json::<Vec<SourceOutput>>(include_str!(
+                "../tests/fixtures/pulse/pipewire/source-outputs.json"
+            )),
+            from_text::<SourceOutput>(include_str!(
+                "../tests/fixtures/pulse/pipewire/source-outputs.txt"
+            )),
+        ] {
+            assert_eq!(outputs.len(), 3);
+            assert_eq!(outputs[0].source, Some(58));
+            assert_eq!(outputs[0].application_name(), Some("Discord"));
+            assert_eq!(outputs[0].binary(), Some("Discord"));
+            assert_eq!(outputs[0].pid(), Some(3310));
+
+            // pavucontrol's meter and OBS reading the speaker monitor aren't recording the mic
+            let recording = recording(outputs, &sources);
+            assert_eq!(recording.len(), 1);
+            assert_eq!(recording[0].index, 120);
+        }
+    }
+
+    #[test]
+    fn device_picker() {
+        let sinks: Vec<Sink> =
+            from_json(include_str!("../tests/fixtures/pulse/pipewire/sinks.json"));
+        let lines: Vec<_> = sinks.iter().map(menu_entry).collect();
+
+        for choice in [&lines[1], &sinks[1].name, &sinks[1].index.to_string()] {
+            assert_eq!(
+                find(&sinks, &format!("{}\n", choice)).unwrap().index,
+                sinks[1].index
+            );
+        }
+        assert!(find(&sinks, "nothing").is_none());
+
+        assert_eq!(next(&sinks, &sinks[0].name).unwrap().index, sinks[1].index);
+        assert_eq!(next(&sinks, &sinks[1].name).unwrap().index, sinks[0].index);
+        assert_eq!(next(&sinks, "unplugged").unwrap().index, sinks[0].index);
+        assert!(next::<Sink>(&[], "unplugged").is_none());
+    }
+
+    #[test]
+    fn cards() {
+        for cards in [
+            from_json::<Vec<Card>>(include_str!("../tests/fixtures/pulse/pipewire/cards.json")),
+            from_text::<Card>(include_str!("../tests/fixtures/pulse/pipewire/cards.txt")),
+        ] {
+            assert_eq!(cards.len(), 1);
+            assert_eq!(cards[0].name, "bluez_card.00_1B_66_A1_B2_C3");
+            assert_eq!(cards[0].active_profile.as_deref(), Some("a2dp-sink"));
+            assert_eq!(cards[0].profiles.len(), 3);
+
+            let hfp = &cards[0].profiles["headset-head-unit"];
+            assert_eq!(hfp.description, "Headset Head Unit (HSP/HFP)");
+            assert_eq!((hfp.sinks, hfp.sources, hfp.priority), (1, 1, 30));
+            assert!(hfp.available);
+
+            let names: Vec<_> = cards[0]
+                .available_profiles()
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect();
+            assert_eq!(names, ["a2dp-sink", "headset-head-unit"]);
+            assert_eq!(cards[0].next_profile(), Some("headset-head-unit"));
+
+            let mut card = cards[0].clone();
+            card.active_profile = Some("headset-head-unit".to_string());
+            assert_eq!(card.next_profile(), Some("a2dp-sink"));
+            card.active_profile = Some("off".to_string());
+            assert_eq!(card.next_profile(), Some("a2dp-sink"));
+        }
+    }
+
+    #[test]
+    fn events() {
+        assert_eq!(
+            "Event 'change' on sink #56".parse::<PulseEvent>().unwrap(),
+            PulseEvent {
+                kind: EventKind::Change,
+                facility: Facility::Sink,
+                index: Some(56),
+            }
+        );
+        assert_eq!(
+            "Event 'new' on source-output #7"
+                .parse::<PulseEvent>()
+                .unwrap(),
+            PulseEvent {
+                kind: EventKind::New,
+                facility: Facility::SourceOutput,
+                index: Some(7),
+            }
+        );
+        assert_eq!(
+            "Event 'change' on server #4294967295"
+                .parse::<PulseEvent>()
+                .unwrap()
+                .index,
+            None
+        );
+        assert!("Event 'moved' on sink #56".parse::<PulseEvent>().is_err());
+        assert!("Event 'change' on sink".parse::<PulseEvent>().is_err());
+        assert!(
+            "Ereignis »change« auf Sink #56"
+                .parse::<PulseEvent>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn server_info() {
+        for info in [
+            from_json::<ServerInfo>(include_str!("../tests/fixtures/pulse/pipewire/info.json")),
+            serde_json::from_value(parse_info(include_str!(
+                "../tests/fixtures/pulse/pipewire/info.txt"
+            )))
+            .unwrap(),
+        ] {
+            assert_eq!(info.server_name, "PulseAudio (on PipeWire 1.0.5)");
+            assert_eq!(
+                info.default_sink_name,
+                "alsa_output.pci-0000_00_1f.3.analog-stereo"
+            );
+            assert_eq!(
+                info.default_source_name,
+                "alsa_input.pci-0000_00_1f.3.analog-stereo"
+            );
+        }
+    }
 }
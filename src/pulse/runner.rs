@@ -0,0 +1,163 @@
+use futures::Stream;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+
+/// What a finished command printed, and whether it exited successfully.
+#[derive(Debug, Clone, Default)]
+pub struct CommandOutput {
+    pub success: bool,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// Lines printed by a long-running command, which is stopped when this is dropped.
+pub type LineStream = Pin<Box<dyn Stream<Item = io::Result<String>>>>;
+
+/// Runs the commands `Pulse` needs. Commands run with `LC_ALL=C`,
+/// so that their text output is the same whatever the user's locale.
+pub trait CommandRunner {
+    /// Runs `program` to completion.
+    fn output(
+        &self,
+        program: &str,
+        args: &[&str],
+    ) -> impl Future<Output = io::Result<CommandOutput>>;
+
+    /// Starts `program` and streams what it prints, line by line.
+    fn lines(&self, program: &str, args: &[&str]) -> io::Result<LineStream>;
+}
+
+/// Spawns real processes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessRunner;
+
+impl CommandRunner for ProcessRunner {
+    async fn output(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+        let output = Command::new(program)
+            .env("LC_ALL", "C")
+            .args(args)
+            .output()
+            .await?;
+
+        Ok(CommandOutput {
+            success: output.status.success(),
+            stdout: output.stdout,
+            stderr: output.stderr,
+        })
+    }
+
+    fn lines(&self, program: &str, args: &[&str]) -> io::Result<LineStream> {
+        let mut child = Command::new(program)
+            .env("LC_ALL", "C")
+            .args(args)
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let stdout = child
+            .stdout
+            .take()
+            .expect("Child process stdout is not available");
+        let lines = BufReader::new(stdout).lines();
+
+        // The child is kept in the state so that it lives, and is killed, with the stream
+        Ok(Box::pin(futures::stream::unfold(
+            Some((child, lines)),
+            |state| async move {
+                let (child, mut lines) = state?;
+                match lines.next_line().await {
+                    Ok(Some(line)) => Some((Ok(line), Some((child, lines)))),
+                    Ok(None) => None,
+                    // Stop after an error rather than failing the same way forever
+                    Err(e) => Some((Err(e), None)),
+                }
+            },
+        )))
+    }
+}
+
+/// Replays captured output instead of running anything, for tests.
+/// Commands without a fixture fail, like an unsupported `pactl -f json` does.
+#[derive(Debug, Default)]
+pub struct FixtureRunner {
+    outputs: HashMap<String, CommandOutput>,
+    calls: RefCell<Vec<String>>,
+}
+
+impl FixtureRunner {
+    pub fn new() -> FixtureRunner {
+        FixtureRunner::default()
+    }
+
+    /// Answers `command`, such as `pactl -f json list sinks`, with `stdout`.
+    pub fn with_output(mut self, command: &str, stdout: &str) -> FixtureRunner {
+        self.outputs.insert(
+            command.to_string(),
+            CommandOutput {
+                success: true,
+                stdout: stdout.as_bytes().to_vec(),
+                stderr: Vec::new(),
+            },
+        );
+        self
+    }
+
+    /// Makes `command` fail, printing `stderr`.
+    pub fn with_failure(mut self, command: &str, stderr: &str) -> FixtureRunner {
+        self.outputs.insert(
+            command.to_string(),
+            CommandOutput {
+                success: false,
+                stdout: Vec::new(),
+                stderr: stderr.as_bytes().to_vec(),
+            },
+        );
+        self
+    }
+
+    /// Every command run so far, in order, as `program arg...`.
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.borrow().clone()
+    }
+
+    fn find(&self, program: &str, args: &[&str]) -> CommandOutput {
+        let command = [&[program], args].concat().join(" ");
+        self.calls.borrow_mut().push(command.clone());
+
+        self.outputs
+            .get(&command)
+            .cloned()
+            .unwrap_or_else(|| CommandOutput {
+                success: false,
+                stdout: Vec::new(),
+                stderr: format!("no fixture for {}", command).into_bytes(),
+            })
+    }
+}
+
+impl CommandRunner for FixtureRunner {
+    async fn output(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+        Ok(self.find(program, args))
+    }
+
+    fn lines(&self, program: &str, args: &[&str]) -> io::Result<LineStream> {
+        let output = self.find(program, args);
+        if !output.success {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                String::from_utf8_lossy(&output.stderr).into_owned(),
+            ));
+        }
+
+        let lines: Vec<_> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|line| Ok(line.to_string()))
+            .collect();
+        Ok(Box::pin(futures::stream::iter(lines)))
+    }
+}
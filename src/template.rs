@@ -0,0 +1,442 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplateError {
+    Unclosed(char),
+    Unexpected(char, usize),
+    EmptyName(usize),
+    Escape(char),
+    Spec(String),
+    Unknown(String, Vec<String>),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::Unclosed(c) => write!(f, "unclosed '{}'", c),
+            TemplateError::Unexpected(c, at) => write!(f, "unexpected '{}' at {}", c, at),
+            TemplateError::EmptyName(at) => write!(f, "empty placeholder name at {}", at),
+            TemplateError::Escape(c) => write!(f, "unknown escape '\\{}'", c),
+            TemplateError::Spec(spec) => write!(f, "invalid format spec '{}'", spec),
+            TemplateError::Unknown(name, known) => write!(
+                f,
+                "unknown variable '{}', expected one of: {}",
+                name,
+                known.join(", ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// A value a binary publishes to its templates.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    Int(i64),
+    Float(f64),
+    Duration(Duration),
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Value {
+        Value::Text(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Value {
+        Value::Text(value)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Value {
+        Value::Int(value)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(value: u32) -> Value {
+        Value::Int(value.into())
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Value {
+        Value::Float(value)
+    }
+}
+
+impl From<Duration> for Value {
+    fn from(value: Duration) -> Value {
+        Value::Duration(value)
+    }
+}
+
+/// The variables a template is rendered with. Unset ones render as nothing.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Vars {
+    values: BTreeMap<String, Value>,
+}
+
+impl Vars {
+    pub fn new() -> Vars {
+        Vars::default()
+    }
+
+    pub fn set(mut self, name: &str, value: impl Into<Value>) -> Vars {
+        self.values.insert(name.to_string(), value.into());
+        self
+    }
+
+    /// Empty text counts as unset, so that sections around it are hidden.
+    fn get(&self, name: &str) -> Option<&Value> {
+        self.values
+            .get(name)
+            .filter(|value| !matches!(value, Value::Text(text) if text.is_empty()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Spec {
+    None,
+    /// `05.1`: zero padded, width 5, one decimal
+    Number {
+        zero: bool,
+        width: usize,
+        precision: Option<usize>,
+    },
+    /// `%H:%M:%S`, for durations and seconds
+    Time(String),
+}
+
+impl FromStr for Spec {
+    type Err = TemplateError;
+
+    fn from_str(spec: &str) -> Result<Spec, TemplateError> {
+        let invalid = || TemplateError::Spec(spec.to_string());
+
+        if spec.contains('%') {
+            let mut chars = spec.chars();
+            while let Some(c) = chars.next() {
+                if c == '%' && !matches!(chars.next(), Some('H' | 'M' | 'S' | '%')) {
+                    return Err(invalid());
+                }
+            }
+            return Ok(Spec::Time(spec.to_string()));
+        }
+
+        let (width, precision) = spec.split_once('.').unwrap_or((spec, ""));
+        let digits = |s: &str| s.parse::<usize>().map_err(|_| invalid());
+        Ok(Spec::Number {
+            zero: width.starts_with('0'),
+            width: if width.is_empty() { 0 } else { digits(width)? },
+            precision: if spec.contains('.') {
+                Some(digits(precision)?)
+            } else {
+                None
+            },
+        })
+    }
+}
+
+fn time(pattern: &str, secs: u64) -> String {
+    let mut out = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('H') => out += &format!("{:0>2}", secs / 3600),
+            Some('M') => out += &format!("{:0>2}", secs / 60 % 60),
+            Some('S') => out += &format!("{:0>2}", secs % 60),
+            _ => out.push('%'),
+        }
+    }
+    out
+}
+
+impl Spec {
+    fn render(&self, value: &Value) -> String {
+        match (self, value) {
+            (Spec::None, Value::Text(text)) => text.clone(),
+            (Spec::None, Value::Int(n)) => n.to_string(),
+            (Spec::None, Value::Float(n)) => n.to_string(),
+            (Spec::None, Value::Duration(d)) if d.as_secs() < 3600 => time("%M:%S", d.as_secs()),
+            (Spec::None, Value::Duration(d)) => time("%H:%M:%S", d.as_secs()),
+            (Spec::Time(pattern), Value::Duration(d)) => time(pattern, d.as_secs()),
+            (Spec::Time(pattern), Value::Int(n)) => time(pattern, (*n).max(0) as u64),
+            // A time pattern makes no sense for the rest, so it's ignored
+            (Spec::Time(_), value) => Spec::None.render(value),
+            (
+                Spec::Number {
+                    zero,
+                    width,
+                    precision,
+                },
+                value,
+            ) => {
+                let text = match (precision, value) {
+                    (Some(p), Value::Int(n)) => format!("{:.*}", p, *n as f64),
+                    (Some(p), Value::Float(n)) => format!("{:.*}", p, n),
+                    (Some(p), Value::Duration(d)) => format!("{:.*}", p, d.as_secs_f64()),
+                    (None, Value::Duration(d)) => d.as_secs().to_string(),
+                    _ => Spec::None.render(value),
+                };
+                let pad = if *zero { '0' } else { ' ' };
+                let missing = width.saturating_sub(text.chars().count());
+                std::iter::repeat_n(pad, missing)
+                    .chain(text.chars())
+                    .collect()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Var(String, Spec),
+    /// Only shown when every variable directly inside is set
+    Section(Vec<Part>),
+}
+
+/// A format string such as `{icon} [{power:.1}W]`.
+///
+/// `{name}` and `{name:spec}` are replaced by variables, where spec is a
+/// number format like `.1` or `03`, or a time format like `%M:%S`. What's
+/// between `[` and `]` is left out unless all its variables are set.
+/// `\{`, `\}`, `\[`, `\]`, `\\` and `\n` escape.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    source: String,
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Template, TemplateError> {
+        let mut chars = source.char_indices();
+        let mut stack: Vec<Vec<Part>> = vec![Vec::new()];
+        let mut text = String::new();
+
+        let flush = |text: &mut String, stack: &mut Vec<Vec<Part>>| {
+            if !text.is_empty() {
+                let parts = stack.last_mut().expect("Template stack is empty");
+                parts.push(Part::Text(std::mem::take(text)));
+            }
+        };
+
+        while let Some((at, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => text.push('\n'),
+                    Some((_, c @ ('{' | '}' | '[' | ']' | '\\'))) => text.push(c),
+                    Some((_, c)) => return Err(TemplateError::Escape(c)),
+                    None => return Err(TemplateError::Escape(' ')),
+                },
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '}')) => break,
+                            Some((_, c)) => placeholder.push(c),
+                            None => return Err(TemplateError::Unclosed('{')),
+                        }
+                    }
+
+                    let (name, spec) = match placeholder.split_once(':') {
+                        Some((name, spec)) => (name, Some(spec)),
+                        None => (placeholder.as_str(), None),
+                    };
+                    if name.is_empty() {
+                        return Err(TemplateError::EmptyName(at));
+                    }
+                    let spec = match spec {
+                        Some(spec) => spec.parse()?,
+                        None => Spec::None,
+                    };
+
+                    flush(&mut text, &mut stack);
+                    let parts = stack.last_mut().expect("Template stack is empty");
+                    parts.push(Part::Var(name.to_string(), spec));
+                }
+                '[' => {
+                    flush(&mut text, &mut stack);
+                    stack.push(Vec::new());
+                }
+                ']' if stack.len() > 1 => {
+                    flush(&mut text, &mut stack);
+                    let section = stack.pop().expect("Template stack is empty");
+                    let parts = stack.last_mut().expect("Template stack is empty");
+                    parts.push(Part::Section(section));
+                }
+                '}' | ']' => return Err(TemplateError::Unexpected(c, at)),
+                c => text.push(c),
+            }
+        }
+
+        if stack.len() > 1 {
+            return Err(TemplateError::Unclosed('['));
+        }
+        flush(&mut text, &mut stack);
+
+        Ok(Template {
+            source: source.to_string(),
+            parts: stack.pop().expect("Template stack is empty"),
+        })
+    }
+
+    pub fn render(&self, vars: &Vars) -> String {
+        let mut out = String::new();
+        render(&self.parts, vars, &mut out);
+        out
+    }
+
+    /// Fails on the first variable that isn't one of `known`.
+    pub fn check(&self, known: &[&str]) -> Result<(), TemplateError> {
+        fn check(parts: &[Part], known: &[&str]) -> Result<(), TemplateError> {
+            for part in parts {
+                match part {
+                    Part::Var(name, _) if !known.contains(&name.as_str()) => {
+                        return Err(TemplateError::Unknown(
+                            name.clone(),
+                            known.iter().map(|k| k.to_string()).collect(),
+                        ));
+                    }
+                    Part::Section(parts) => check(parts, known)?,
+                    _ => {}
+                }
+            }
+            Ok(())
+        }
+
+        check(&self.parts, known)
+    }
+}
+
+fn render(parts: &[Part], vars: &Vars, out: &mut String) {
+    for part in parts {
+        match part {
+            Part::Text(text) => out.push_str(text),
+            Part::Var(name, spec) => {
+                if let Some(value) = vars.get(name) {
+                    out.push_str(&spec.render(value));
+                }
+            }
+            Part::Section(parts) => {
+                let shown = parts.iter().all(|part| match part {
+                    Part::Var(name, _) => vars.get(name).is_some(),
+                    _ => true,
+                });
+                if shown {
+                    render(parts, vars, out);
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Template {
+    type Err = TemplateError;
+
+    fn from_str(source: &str) -> Result<Template, TemplateError> {
+        Template::parse(source)
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = TemplateError;
+
+    fn try_from(source: String) -> Result<Template, TemplateError> {
+        Template::parse(&source)
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, vars: &Vars) -> String {
+        Template::parse(template).unwrap().render(vars)
+    }
+
+    #[test]
+    fn placeholders() {
+        let vars = Vars::new()
+            .set("icon", "B")
+            .set("capacity", 42u32)
+            .set("power", 12.345)
+            .set("elapsed", Duration::from_secs(125));
+
+        assert_eq!(render("{icon} {capacity}%", &vars), "B 42%");
+        assert_eq!(render("{power:.1}W", &vars), "12.3W");
+        assert_eq!(render("{capacity:05.1}", &vars), "042.0");
+        assert_eq!(render("{capacity:4}|", &vars), "  42|");
+        assert_eq!(render("{elapsed:%M:%S}", &vars), "02:05");
+        assert_eq!(render("{elapsed}", &vars), "02:05");
+        assert_eq!(
+            render(
+                "{elapsed}",
+                &Vars::new().set("elapsed", Duration::from_secs(3725))
+            ),
+            "01:02:05"
+        );
+        assert_eq!(render("{missing}!", &vars), "!");
+    }
+
+    #[test]
+    fn sections() {
+        let vars = Vars::new().set("device", "Speakers").set("port", "");
+
+        assert_eq!(render("{device}[\\n{port}]", &vars), "Speakers");
+        assert_eq!(
+            render(
+                "{device}[\\n{port}]",
+                &vars.clone().set("port", "Headphones")
+            ),
+            "Speakers\nHeadphones"
+        );
+        assert_eq!(render("[a[{device}][{port}]]", &vars), "aSpeakers");
+        assert_eq!(render("\\[{device}\\]", &vars), "[Speakers]");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Template::parse("{icon"), Err(TemplateError::Unclosed('{')));
+        assert_eq!(
+            Template::parse("[{icon}"),
+            Err(TemplateError::Unclosed('['))
+        );
+        assert_eq!(
+            Template::parse("{icon}]"),
+            Err(TemplateError::Unexpected(']', 6))
+        );
+        assert_eq!(Template::parse("a{}"), Err(TemplateError::EmptyName(1)));
+        assert_eq!(Template::parse("{:.1}"), Err(TemplateError::EmptyName(0)));
+        assert_eq!(
+            Template::parse("{power:.x}"),
+            Err(TemplateError::Spec(".x".to_string()))
+        );
+        assert_eq!(
+            Template::parse("{elapsed:%Q}"),
+            Err(TemplateError::Spec("%Q".to_string()))
+        );
+
+        let template = Template::parse("{icon} [{capcity}%]").unwrap();
+        assert!(template.check(&["icon", "capacity"]).is_err());
+        assert!(template.check(&["icon", "capcity"]).is_ok());
+    }
+}
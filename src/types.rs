@@ -1,7 +1,71 @@
-use serde::Serialize;
+use serde::{Serialize, Serializer};
+use std::borrow::Cow;
 
-#[derive(Serialize)]
+/// One update of a waybar custom module, see waybar-custom(5).
+/// Strings may be borrowed or owned, `into_owned` detaches it from the borrows.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
 pub struct Response<'a> {
-    pub class: &'a str,
-    pub text: &'a str,
+    /// One class is written as a string, several as an array
+    #[serde(skip_serializing_if = "Vec::is_empty", serialize_with = "classes")]
+    pub class: Vec<Cow<'a, str>>,
+    pub text: Cow<'a, str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tooltip: Option<Cow<'a, str>>,
+    /// Picks an entry of the module's `format-icons` by name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alt: Option<Cow<'a, str>>,
+    /// Picks an entry of the module's `format-icons` by level
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percentage: Option<u32>,
+}
+
+fn classes<S: Serializer>(class: &[Cow<str>], serializer: S) -> Result<S::Ok, S::Error> {
+    match class {
+        [class] => serializer.serialize_str(class),
+        _ => class.serialize(serializer),
+    }
+}
+
+impl<'a> Response<'a> {
+    pub fn new(text: impl Into<Cow<'a, str>>) -> Response<'a> {
+        Response {
+            text: text.into(),
+            ..Response::default()
+        }
+    }
+
+    /// Adds a CSS class, can be called repeatedly.
+    pub fn class(mut self, class: impl Into<Cow<'a, str>>) -> Response<'a> {
+        self.class.push(class.into());
+        self
+    }
+
+    pub fn tooltip(mut self, tooltip: impl Into<Cow<'a, str>>) -> Response<'a> {
+        self.tooltip = Some(tooltip.into());
+        self
+    }
+
+    pub fn alt(mut self, alt: impl Into<Cow<'a, str>>) -> Response<'a> {
+        self.alt = Some(alt.into());
+        self
+    }
+
+    pub fn percentage(mut self, percentage: u32) -> Response<'a> {
+        self.percentage = Some(percentage);
+        self
+    }
+
+    pub fn into_owned(self) -> Response<'static> {
+        Response {
+            class: self
+                .class
+                .into_iter()
+                .map(|c| Cow::Owned(c.into_owned()))
+                .collect(),
+            text: Cow::Owned(self.text.into_owned()),
+            tooltip: self.tooltip.map(|t| Cow::Owned(t.into_owned())),
+            alt: self.alt.map(|a| Cow::Owned(a.into_owned())),
+            percentage: self.percentage,
+        }
+    }
 }
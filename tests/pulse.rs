@@ -0,0 +1,424 @@
+use futures::StreamExt;
+use lib::emit::Plain;
+use lib::pulse::{
+    self, Availability, Device, EventKind, Facility, FixtureRunner, Pulse, PulseEvent,
+};
+
+macro_rules! capture {
+    ($server:literal, $file:literal) => {
+        include_str!(concat!("fixtures/pulse/", $server, "/", $file))
+    };
+}
+
+/// PipeWire 1.0 with pactl 16, which prints JSON.
+fn pipewire() -> FixtureRunner {
+    FixtureRunner::new()
+        .with_output("pactl -f json info", capture!("pipewire", "info.json"))
+        .with_output(
+            "pactl -f json list sinks",
+            capture!("pipewire", "sinks.json"),
+        )
+        .with_output(
+            "pactl -f json list sources",
+            capture!("pipewire", "sources.json"),
+        )
+        .with_output(
+            "pactl -f json list sink-inputs",
+            capture!("pipewire", "sink-inputs.json"),
+        )
+        .with_output(
+            "pactl -f json list source-outputs",
+            capture!("pipewire", "source-outputs.json"),
+        )
+        .with_output(
+            "pactl -f json list cards",
+            capture!("pipewire", "cards.json"),
+        )
+        .with_output("pactl subscribe", capture!("pipewire", "subscribe.txt"))
+}
+
+/// The same PipeWire session captured as C-locale text.
+fn pipewire_text() -> FixtureRunner {
+    FixtureRunner::new()
+        .with_output("pactl info", capture!("pipewire", "info.txt"))
+        .with_output("pactl list sinks", capture!("pipewire", "sinks.txt"))
+        .with_output("pactl list sources", capture!("pipewire", "sources.txt"))
+        .with_output(
+            "pactl list sink-inputs",
+            capture!("pipewire", "sink-inputs.txt"),
+        )
+        .with_output(
+            "pactl list source-outputs",
+            capture!("pipewire", "source-outputs.txt"),
+        )
+        .with_output("pactl list cards", capture!("pipewire", "cards.txt"))
+}
+
+/// PulseAudio 15, whose pactl has no `-f json`.
+fn pulseaudio() -> FixtureRunner {
+    let mut runner = FixtureRunner::new();
+    for args in [
+        "info",
+        "list sinks",
+        "list sources",
+        "list sink-inputs",
+        "list source-outputs",
+        "list cards",
+    ] {
+        runner = runner.with_failure(
+            &format!("pactl -f json {}", args),
+            "pactl: invalid option -- 'f'",
+        );
+    }
+
+    runner
+        .with_output("pactl info", capture!("pulseaudio", "info.txt"))
+        .with_output("pactl list sinks", capture!("pulseaudio", "sinks.txt"))
+        .with_output("pactl list sources", capture!("pulseaudio", "sources.txt"))
+        .with_output(
+            "pactl list sink-inputs",
+            capture!("pulseaudio", "sink-inputs.txt"),
+        )
+        .with_output(
+            "pactl list source-outputs",
+            capture!("pulseaudio", "source-outputs.txt"),
+        )
+        .with_output("pactl list cards", capture!("pulseaudio", "cards.txt"))
+        .with_output("pactl subscribe", capture!("pulseaudio", "subscribe.txt"))
+}
+
+#[tokio::test]
+async fn pipewire_devices() {
+    for pulse in [
+        Pulse::with_runner(pipewire()),
+        Pulse::with_runner(pipewire_text()),
+    ] {
+        let sink = pulse.output_device().await.unwrap().unwrap();
+        assert_eq!(sink.index, 56);
+        assert_eq!(sink.volume.percent(), 65);
+        assert_eq!(sink.channel_map, "front-left,front-right");
+        assert!(!pulse.is_output_muted().await.unwrap());
+
+        let source = pulse.input_device().await.unwrap().unwrap();
+        assert_eq!(source.index, 58);
+        assert!(pulse.is_input_muted().await.unwrap());
+
+        let recording = pulse.recording_streams().await.unwrap();
+        assert_eq!(recording.len(), 1);
+        assert_eq!(recording[0].application_name(), Some("Discord"));
+
+        let cards = pulse.list_cards().await.unwrap();
+        assert_eq!(cards[0].next_profile(), Some("headset-head-unit"));
+    }
+}
+
+#[tokio::test]
+async fn pulseaudio_devices() {
+    let pulse = Pulse::with_runner(pulseaudio());
+
+    let info = pulse.server_info().await.unwrap();
+    assert_eq!(info.server_name, "pulseaudio");
+    assert_eq!(info.server_version, "15.0");
+
+    let sinks = pulse.list_sinks().await.unwrap();
+    assert_eq!(sinks.len(), 2);
+    assert_eq!(sinks[0].index, 0);
+    assert_eq!(sinks[0].driver, "module-alsa-card.c");
+    assert_eq!(sinks[0].volume.percent(), 50);
+    assert_eq!(sinks[0].volume.level(), "medium");
+    assert_eq!(sinks[0].channel_map, "front-left,front-right");
+    assert_eq!(
+        sinks[0].monitor_source,
+        "alsa_output.pci-0000_00_1f.3.analog-stereo.monitor"
+    );
+    assert_eq!(sinks[0].active_port().unwrap().kind, "Headphones");
+    assert_eq!(sinks[0].ports[0].availability, Availability::NotAvailable);
+    assert_eq!(sinks[1].active_port().unwrap().kind, "HDMI");
+    assert!(sinks[1].mute);
+
+    let sink = pulse.output_device().await.unwrap().unwrap();
+    assert_eq!(sink.index, 0);
+    assert!(!pulse.is_output_muted().await.unwrap());
+
+    let source = pulse.input_device().await.unwrap().unwrap();
+    assert_eq!(source.index, 1);
+    assert_eq!(source.monitor_of_sink, None);
+    assert_eq!(source.volume.percent(), 70);
+    assert_eq!(source.base_volume.db, -60.0);
+
+    let inputs = pulse.list_sink_inputs().await.unwrap();
+    assert_eq!(inputs.len(), 1);
+    assert_eq!((inputs[0].index, inputs[0].sink), (5, Some(0)));
+    assert!(inputs[0].corked);
+
+    // pavucontrol's meter and parec reading the monitor aren't recording the mic
+    let recording = pulse.recording_streams().await.unwrap();
+    assert_eq!(recording.len(), 2);
+    assert_eq!(recording[0].index, 4);
+    assert_eq!(recording[0].binary(), Some("chromium"));
+    assert_eq!(recording[0].pid(), Some(5120));
+    assert_eq!(
+        recording[0].resample_method.as_deref(),
+        Some("speex-float-1")
+    );
+
+    // A loopback has neither a client nor a resampler
+    assert_eq!(recording[1].index, 6);
+    assert_eq!(recording[1].client, None);
+    assert_eq!(recording[1].resample_method, None);
+
+    let cards = pulse.list_cards().await.unwrap();
+    assert_eq!(cards.len(), 1);
+    assert_eq!(cards[0].description(), Some("Built-in Audio"));
+    assert_eq!(cards[0].profiles.len(), 5);
+    assert_eq!(
+        cards[0].active_profile.as_deref(),
+        Some("output:analog-stereo+input:analog-stereo")
+    );
+    assert!(!cards[0].profiles["output:hdmi-stereo"].available);
+    assert_eq!(cards[0].next_profile(), Some("output:analog-stereo"));
+}
+
+#[tokio::test]
+async fn broken_json_falls_back_to_text() {
+    let runner = pipewire_text()
+        .with_output("pactl -f json list sinks", "[{\"index\":56,")
+        .with_output("pactl -f json info", capture!("pipewire", "info.json"));
+    let pulse = Pulse::with_runner(runner);
+
+    assert_eq!(pulse.list_sinks().await.unwrap().len(), 2);
+    assert_eq!(
+        pulse.runner().calls(),
+        ["pactl -f json list sinks", "pactl list sinks"]
+    );
+}
+
+#[tokio::test]
+async fn errors_carry_pactl_stderr() {
+    let runner = FixtureRunner::new().with_failure(
+        "pactl list sinks",
+        "Connection failure: Connection refused\n",
+    );
+    let pulse = Pulse::with_runner(runner);
+
+    let error = pulse.list_sinks().await.unwrap_err();
+    assert_eq!(error.to_string(), "Connection failure: Connection refused");
+}
+
+#[tokio::test]
+async fn change_volume() {
+    let runner = pipewire()
+        .with_output("pactl -- set-sink-volume @DEFAULT_SINK@ +5%", "")
+        .with_output("pactl set-sink-volume @DEFAULT_SINK@ 65536 65536", "")
+        .with_output("pactl -- set-source-volume 58 -50%", "");
+    let pulse = Pulse::with_runner(runner);
+    let sink = Device::Sink.default_name();
+
+    assert_eq!(
+        pulse
+            .change_volume(Device::Sink, sink, 5, 100)
+            .await
+            .unwrap(),
+        70
+    );
+    assert_eq!(
+        pulse
+            .change_volume(Device::Sink, sink, 50, 100)
+            .await
+            .unwrap(),
+        100
+    );
+    assert_eq!(
+        pulse
+            .change_volume(Device::Source, "58", -50, 100)
+            .await
+            .unwrap(),
+        0
+    );
+
+    // Already above the cap, so neither raised nor pulled down
+    let headset = "bluez_output.00_1B_66_A1_B2_C3.1";
+    assert_eq!(
+        pulse
+            .change_volume(Device::Sink, headset, 5, 100)
+            .await
+            .unwrap(),
+        125
+    );
+
+    let sets: Vec<_> = pulse
+        .runner()
+        .calls()
+        .into_iter()
+        .filter(|call| call.contains(" set-"))
+        .collect();
+    assert_eq!(
+        sets,
+        [
+            "pactl -- set-sink-volume @DEFAULT_SINK@ +5%",
+            "pactl set-sink-volume @DEFAULT_SINK@ 65536 65536",
+            "pactl -- set-source-volume 58 -50%",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn change_volume_keeps_balance() {
+    // The headset is at 150% left and 100% right
+    let headset = "bluez_output.00_1B_66_A1_B2_C3.1";
+    let runner = pipewire()
+        .with_output(&format!("pactl -- set-sink-volume {} +5%", headset), "")
+        .with_output(&format!("pactl -- set-sink-volume {} -10%", headset), "")
+        .with_output(
+            &format!("pactl set-sink-volume {} 104857 69904", headset),
+            "",
+        );
+    let pulse = Pulse::with_runner(runner);
+
+    assert_eq!(
+        pulse
+            .change_volume(Device::Sink, headset, 5, 200)
+            .await
+            .unwrap(),
+        130
+    );
+    assert_eq!(
+        pulse
+            .change_volume(Device::Sink, headset, -10, 200)
+            .await
+            .unwrap(),
+        115
+    );
+
+    // Past the cap the loudest channel lands on it and the right stays two thirds of it
+    assert_eq!(
+        pulse
+            .change_volume(Device::Sink, headset, 20, 160)
+            .await
+            .unwrap(),
+        133
+    );
+
+    let sets: Vec<_> = pulse
+        .runner()
+        .calls()
+        .into_iter()
+        .filter(|call| call.contains("set-sink-volume"))
+        .collect();
+    assert_eq!(
+        sets,
+        [
+            format!("pactl -- set-sink-volume {} +5%", headset),
+            format!("pactl -- set-sink-volume {} -10%", headset),
+            format!("pactl set-sink-volume {} 104857 69904", headset),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn set_default_moves_streams() {
+    let headset = "bluez_output.00_1B_66_A1_B2_C3.1";
+    let mic = "alsa_input.pci-0000_00_1f.3.analog-stereo";
+    let runner = pipewire()
+        .with_output(&format!("pactl set-default-sink {}", headset), "")
+        .with_output(&format!("pactl move-sink-input 91 {}", headset), "")
+        .with_output(&format!("pactl set-default-source {}", mic), "")
+        .with_output(&format!("pactl move-source-output 120 {}", mic), "");
+    let pulse = Pulse::with_runner(runner);
+
+    pulse.set_default_sink(headset, true).await.unwrap();
+    pulse.set_default_source(mic, true).await.unwrap();
+
+    // Only the stream recording the mic moves, not pavucontrol's meter or OBS
+    let changes: Vec<_> = pulse
+        .runner()
+        .calls()
+        .into_iter()
+        .filter(|call| !call.starts_with("pactl -f json"))
+        .collect();
+    assert_eq!(
+        changes,
+        [
+            format!("pactl set-default-sink {}", headset),
+            format!("pactl move-sink-input 91 {}", headset),
+            format!("pactl set-default-source {}", mic),
+            format!("pactl move-source-output 120 {}", mic),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn subscribe() {
+    for (runner, first) in [
+        (
+            pipewire(),
+            PulseEvent {
+                kind: EventKind::Change,
+                facility: Facility::Sink,
+                index: Some(56),
+            },
+        ),
+        (
+            pulseaudio(),
+            PulseEvent {
+                kind: EventKind::New,
+                facility: Facility::SinkInput,
+                index: Some(5),
+            },
+        ),
+    ] {
+        let pulse = Pulse::with_runner(runner);
+        let events: Vec<_> = pulse
+            .subscribe()
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+
+        assert_eq!(events.len(), 5);
+        assert_eq!(events[0], first);
+        assert!(
+            events
+                .iter()
+                .any(|e| e.facility == Facility::Server && e.index.is_none())
+        );
+    }
+}
+
+#[tokio::test]
+async fn subscribe_reports_bad_lines() {
+    let runner = FixtureRunner::new().with_output(
+        "pactl subscribe",
+        "Event 'change' on sink #1\nEreignis »change« auf Sink #1\n",
+    );
+    let pulse = Pulse::with_runner(runner);
+
+    let events: Vec<_> = pulse.subscribe().unwrap().collect().await;
+    assert!(events[0].is_ok());
+    assert!(events[1].is_err());
+}
+
+#[tokio::test]
+async fn handle_events_skips_bad_lines() {
+    let runner = FixtureRunner::new().with_output(
+        "pactl subscribe",
+        "Event 'change' on sink #1\nEreignis »change« auf Sink #1\nEvent 'new' on card #2\n",
+    );
+    let pulse = Pulse::with_runner(runner);
+
+    let mut seen = Vec::new();
+    pulse::handle_events(pulse.subscribe().unwrap(), &mut Plain, async |_, event| {
+        seen.push(event.facility);
+        Ok(())
+    })
+    .await
+    .unwrap();
+    assert_eq!(seen, [Facility::Sink, Facility::Card]);
+
+    // The handler's errors end it
+    let result = pulse::handle_events(pulse.subscribe().unwrap(), &mut Plain, async |_, _| {
+        Err("card went away".into())
+    })
+    .await;
+    assert_eq!(result.unwrap_err().to_string(), "card went away");
+}